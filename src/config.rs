@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
@@ -5,6 +6,35 @@ use std::path::PathBuf;
 #[derive(Deserialize)]
 pub struct Config {
     pub music_dir: String,
+    // Seconds the end of a song overlaps the start of the next one from a
+    // different album. Zero turns crossfading off.
+    #[serde(default)]
+    pub crossfade_secs: u64,
+    // File extensions scanned for music, in any case. Files are still
+    // checked to be audio whatever they are called.
+    #[serde(default = "default_extensions")]
+    pub extensions: Vec<String>,
+    // Artist pane entry compilations are listed under.
+    #[serde(default = "default_various_artists")]
+    pub various_artists: String,
+    // Smart playlists, each a query whose songs it plays, by name.
+    #[serde(default)]
+    pub playlists: BTreeMap<String, String>,
+}
+
+fn default_various_artists() -> String {
+    return "Various Artists".to_string();
+}
+
+fn default_extensions() -> Vec<String> {
+    let extensions = [
+        "flac", "mp3", "wav", "ogg", "oga", "opus", "m4a", "mp4", "aif",
+        "aiff", "aifc", "wv",
+    ];
+    return extensions
+        .iter()
+        .map(|extension| extension.to_string())
+        .collect();
 }
 
 impl Config {
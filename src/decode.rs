@@ -0,0 +1,646 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::time::Duration;
+
+use audiopus::coder::Decoder as OpusDecoder;
+use audiopus::{Channels, SampleRate};
+use ogg::reading::PacketReader;
+use ogg::Packet;
+use rodio::Source;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::{Time, TimeBase};
+
+use crate::metadata::Format;
+use crate::wavpack::{self, Header};
+
+// Opus is always decoded at 48kHz.
+const OPUS_RATE: u32 = 48000;
+// Samples per channel in the longest Opus packet, 120ms.
+const OPUS_MAX_FRAMES: usize = 5760;
+// Samples per channel the Opus decoder needs after a seek before its output
+// is right, 80ms.
+const OPUS_PREROLL: u64 = 3840;
+
+// Decoded samples of a song, whatever format it is in.
+pub type AudioSource = Box<dyn Source<Item = i16> + Send>;
+
+// Open the file at `path` with a decoder for the format it holds, seeking
+// to `start` into the song. Opus and WavPack have decoders of their own,
+// symphonia does everything else.
+pub fn open(path: &Path, start: Duration) -> Result<AudioSource, String> {
+    let format =
+        Format::of_file(path).ok_or("not a recognised audio format")?;
+    let file = File::open(path).map_err(|error| error.to_string())?;
+    match format {
+        Format::Opus => return Ok(Box::new(OpusSource::new(file, start)?)),
+        Format::WavPack => {
+            return Ok(Box::new(WavPackSource::new(file, start)?));
+        }
+        _ => return Ok(Box::new(SymphoniaSource::new(file, start)?)),
+    }
+}
+
+// Decoded by symphonia: FLAC, MP3, WAV, Vorbis, MP4 (AAC or ALAC) and AIFF.
+//
+// Lossy encoders add silence at both ends of a song. Symphonia trims it off
+// MP3 (going by the LAME tag) and Vorbis, MP4 says how much there is in its
+// iTunSMPB tag, which is read here.
+struct SymphoniaSource {
+    reader: Box<dyn FormatReader>,
+    decoder: Box<dyn symphonia::core::codecs::Decoder>,
+    track: u32,
+    time_base: Option<TimeBase>,
+    buffer: Option<SampleBuffer<i16>>,
+    // How much of `buffer` the last packet filled, less any padding.
+    decoded: usize,
+    // Next sample in `buffer` to hand out.
+    position: usize,
+    channels: u16,
+    sample_rate: u32,
+    duration: Option<Duration>,
+    // Frames from the first to hand out up to the one after the last.
+    // Seeks land on a packet at or before the wanted point, so some of the
+    // first one can be early.
+    first: u64,
+    end: Option<u64>,
+}
+
+impl SymphoniaSource {
+    fn new(file: File, start: Duration) -> Result<SymphoniaSource, String> {
+        let stream = MediaSourceStream::new(Box::new(file), Default::default());
+        let options = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let probed = symphonia::default::get_probe()
+            .format(&Hint::new(), stream, &options, &MetadataOptions::default())
+            .map_err(|error| error.to_string())?;
+        let mut reader = probed.format;
+        let track = reader.default_track().ok_or("no audio track")?;
+        let track_id = track.id;
+        let mut frames = track.codec_params.n_frames;
+        let time_base = track.codec_params.time_base;
+        let rate = track.codec_params.sample_rate.unwrap_or(0);
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .map_err(|error| error.to_string())?;
+        let (delay, end) = match itunes_gapless(&mut reader) {
+            Some((delay, length)) => {
+                frames = Some(length);
+                (delay, Some(delay + length))
+            }
+            None => (0, None),
+        };
+        let mut first = delay;
+        if start > Duration::new(0, 0) {
+            let start = start + frames_to_duration(delay, rate);
+            let time = Time::new(
+                start.as_secs(),
+                start.subsec_nanos() as f64 / 1_000_000_000.0,
+            );
+            let to = SeekTo::Time {
+                time: time,
+                track_id: Some(track_id),
+            };
+            let seeked = reader
+                .seek(SeekMode::Accurate, to)
+                .map_err(|error| error.to_string())?;
+            first = ts_to_frames(seeked.required_ts, time_base, rate);
+        }
+        let mut source = SymphoniaSource {
+            reader: reader,
+            decoder: decoder,
+            track: track_id,
+            time_base: time_base,
+            buffer: None,
+            decoded: 0,
+            position: 0,
+            channels: 0,
+            sample_rate: 0,
+            duration: None,
+            first: first,
+            end: end,
+        };
+        // Not every file says its channels up front, the first packet does.
+        if !source.decode_next() {
+            return Err("no audio in file".to_string());
+        }
+        source.duration =
+            frames.map(|frames| frames_to_duration(frames, source.sample_rate));
+        return Ok(source);
+    }
+
+    // Decode the next packet of the track into the buffer. False at the end
+    // of the file.
+    fn decode_next(&mut self) -> bool {
+        loop {
+            let packet = match self.reader.next_packet() {
+                Ok(packet) => packet,
+                Err(_) => return false,
+            };
+            if packet.track_id() != self.track {
+                continue;
+            }
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // A damaged packet is skipped, like a CD player would.
+                Err(SymphoniaError::DecodeError(_)) => continue,
+                Err(_) => return false,
+            };
+            let spec = *decoded.spec();
+            let start = ts_to_frames(packet.ts(), self.time_base, spec.rate);
+            let mut end = start + decoded.frames() as u64;
+            if let Some(last) = self.end {
+                if start >= last {
+                    return false;
+                }
+                end = end.min(last);
+            }
+            if end <= self.first {
+                continue;
+            }
+            let needed = decoded.capacity() * spec.channels.count();
+            let too_small = match self.buffer {
+                Some(ref buffer) => buffer.capacity() < needed,
+                None => true,
+            };
+            if too_small {
+                let frames = decoded.capacity() as u64;
+                self.buffer = Some(SampleBuffer::new(frames, spec));
+            }
+            if let Some(ref mut buffer) = self.buffer {
+                buffer.copy_interleaved_ref(decoded);
+            }
+            let channels = spec.channels.count();
+            self.channels = channels as u16;
+            self.sample_rate = spec.rate;
+            self.decoded = (end - start) as usize * channels;
+            self.position =
+                self.first.saturating_sub(start) as usize * channels;
+            return true;
+        }
+    }
+}
+
+// Encoder delay and length in frames of an MP4 song, from the iTunSMPB tag
+// iTunes and most other AAC encoders write.
+fn itunes_gapless(reader: &mut Box<dyn FormatReader>) -> Option<(u64, u64)> {
+    let mut metadata = reader.metadata();
+    let revision = metadata.skip_to_latest()?;
+    let tag = revision
+        .tags()
+        .iter()
+        .find(|tag| tag.key.ends_with("iTunSMPB"))?;
+    return parse_itunsmpb(&tag.value.to_string());
+}
+
+// The tag holds hex numbers, the second being the delay, the third the
+// padding and the fourth the length.
+fn parse_itunsmpb(value: &str) -> Option<(u64, u64)> {
+    let fields: Vec<u64> = value
+        .split_whitespace()
+        .map(|field| u64::from_str_radix(field, 16).ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if fields.len() < 4 || fields[3] == 0 {
+        return None;
+    }
+    return Some((fields[1], fields[3]));
+}
+
+fn frames_to_duration(frames: u64, rate: u32) -> Duration {
+    if rate == 0 {
+        return Duration::new(0, 0);
+    }
+    let nanos = (frames % rate as u64) * 1_000_000_000 / rate as u64;
+    return Duration::new(frames / rate as u64, nanos as u32);
+}
+
+// Frames at `rate` that a timestamp in `time_base` units comes to. The two
+// are the same for almost every track.
+fn ts_to_frames(ts: u64, time_base: Option<TimeBase>, rate: u32) -> u64 {
+    match time_base {
+        Some(base) => {
+            return ts * base.numer as u64 * rate as u64 / base.denom as u64;
+        }
+        None => return ts,
+    }
+}
+
+impl Iterator for SymphoniaSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        loop {
+            if let Some(ref buffer) = self.buffer {
+                if self.position < self.decoded {
+                    self.position += 1;
+                    return Some(buffer.samples()[self.position - 1]);
+                }
+            }
+            if !self.decode_next() {
+                return None;
+            }
+        }
+    }
+}
+
+impl Source for SymphoniaSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        return None;
+    }
+
+    fn channels(&self) -> u16 {
+        return self.channels;
+    }
+
+    fn sample_rate(&self) -> u32 {
+        return self.sample_rate;
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        return self.duration;
+    }
+}
+
+// Opus in an Ogg file, decoded by libopus. Only mono and stereo, which is
+// all music is in practice.
+struct OpusSource {
+    packets: PacketReader<BufReader<File>>,
+    // Packets read while seeking, to be decoded before any more are read.
+    pending: VecDeque<Packet>,
+    decoder: OpusDecoder,
+    channels: u16,
+    // Granule position, in samples per channel, of the next packet.
+    granule: u64,
+    // Granule position of the first sample to hand out. Those before it
+    // only prime the decoder, or come before where playback starts.
+    first: u64,
+    buffer: Vec<i16>,
+    // How much of `buffer` the last packet filled.
+    decoded: usize,
+    // Next sample in `buffer` to hand out.
+    position: usize,
+}
+
+impl OpusSource {
+    fn new(file: File, start: Duration) -> Result<OpusSource, String> {
+        let mut packets = PacketReader::new(BufReader::new(file));
+        let head = match packets.read_packet() {
+            Ok(Some(packet)) => packet.data,
+            _ => return Err("no Opus header".to_string()),
+        };
+        if head.len() < 19 || &head[0..8] != b"OpusHead" {
+            return Err("no Opus header".to_string());
+        }
+        let (channels, count) = match head[9] {
+            1 => (Channels::Mono, 1),
+            2 => (Channels::Stereo, 2),
+            _ => return Err("only mono and stereo Opus can be played".into()),
+        };
+        let pre_skip = u16::from_le_bytes([head[10], head[11]]) as u64;
+        let decoder = OpusDecoder::new(SampleRate::Hz48000, channels)
+            .map_err(|error| error.to_string())?;
+        // The comment header is next, tags are read by lofty.
+        let _ = packets.read_packet();
+        let first = pre_skip + duration_to_frames(start, OPUS_RATE);
+        let mut source = OpusSource {
+            packets: packets,
+            pending: VecDeque::new(),
+            decoder: decoder,
+            channels: count,
+            granule: 0,
+            first: first,
+            buffer: vec![0; OPUS_MAX_FRAMES * count as usize],
+            decoded: 0,
+            position: 0,
+        };
+        if start > Duration::new(0, 0) {
+            source.seek()?;
+        }
+        return Ok(source);
+    }
+
+    // Go to the page the decoder has to start on to play from `first`.
+    // Pages only say where their last packet ends, so where the page starts
+    // comes from the length of each packet on it.
+    fn seek(&mut self) -> Result<(), String> {
+        // The page found can start up to a packet after the one asked for.
+        let goal = self
+            .first
+            .saturating_sub(OPUS_PREROLL + OPUS_MAX_FRAMES as u64);
+        if goal == 0 {
+            return Ok(());
+        }
+        match self.packets.seek_absgp(None, goal) {
+            Ok(true) => {}
+            _ => return Err("can't seek that far".to_string()),
+        }
+        let mut frames = 0;
+        while let Ok(Some(packet)) = self.packets.read_packet() {
+            frames += opus_packet_frames(&packet.data);
+            let end = packet.absgp_page();
+            let last = packet.last_in_page();
+            self.pending.push_back(packet);
+            if last {
+                self.granule = end.saturating_sub(frames);
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    // Decode the next packet into the buffer. False at the end of the file.
+    fn decode_next(&mut self) -> bool {
+        loop {
+            let packet = match self.pending.pop_front() {
+                Some(packet) => packet,
+                None => match self.packets.read_packet() {
+                    Ok(Some(packet)) => packet,
+                    _ => return false,
+                },
+            };
+            let frames = match self.decoder.decode(
+                Some(&packet.data[..]),
+                &mut self.buffer[..],
+                false,
+            ) {
+                Ok(frames) => frames,
+                Err(_) => continue,
+            };
+            let start = self.granule;
+            let mut end = start + frames as u64;
+            // The last packet is padded out to a whole frame, its page says
+            // where the song really ends.
+            if packet.last_in_stream() {
+                end = end.min(packet.absgp_page().max(start));
+            }
+            self.granule = end;
+            if end <= self.first {
+                continue;
+            }
+            let skipped = self.first.saturating_sub(start) as usize;
+            self.decoded = (end - start) as usize * self.channels as usize;
+            self.position = skipped * self.channels as usize;
+            return true;
+        }
+    }
+}
+
+// Samples per channel in an Opus packet, going by its TOC byte: the frame
+// length its configuration gives, times the number of frames.
+fn opus_packet_frames(data: &[u8]) -> u64 {
+    let toc = match data.first() {
+        Some(&toc) => toc,
+        None => return 0,
+    };
+    let config = (toc >> 3) as usize;
+    let frame = match config {
+        // SILK, 10, 20, 40 or 60ms.
+        0..=11 => [480, 960, 1920, 2880][config % 4],
+        // Hybrid, 10 or 20ms.
+        12..=15 => [480, 960][config % 2],
+        // CELT, 2.5, 5, 10 or 20ms.
+        _ => [120, 240, 480, 960][config % 4],
+    };
+    let count = match toc & 3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => data.get(1).map_or(0, |&byte| byte & 0x3f) as u64,
+    };
+    return frame * count;
+}
+
+fn duration_to_frames(duration: Duration, rate: u32) -> u64 {
+    return duration.as_secs() * rate as u64
+        + duration.subsec_nanos() as u64 * rate as u64 / 1_000_000_000;
+}
+
+impl Iterator for OpusSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.position >= self.decoded && !self.decode_next() {
+            return None;
+        }
+        let sample = self.buffer[self.position];
+        self.position += 1;
+        return Some(sample);
+    }
+}
+
+impl Source for OpusSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        return None;
+    }
+
+    fn channels(&self) -> u16 {
+        return self.channels;
+    }
+
+    fn sample_rate(&self) -> u32 {
+        return OPUS_RATE;
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        return None;
+    }
+}
+
+// WavPack, decoded by the wavpack module a frame at a time: the blocks that
+// cover the same stretch of the song, a channel pair each.
+struct WavPackSource {
+    file: BufReader<File>,
+    channels: u16,
+    sample_rate: u32,
+    duration: Option<Duration>,
+    buffer: Vec<i16>,
+    // Next sample in `buffer` to hand out.
+    position: usize,
+}
+
+impl WavPackSource {
+    fn new(file: File, start: Duration) -> Result<WavPackSource, String> {
+        let mut source = WavPackSource {
+            file: BufReader::new(file),
+            channels: 0,
+            sample_rate: 0,
+            duration: None,
+            buffer: Vec::new(),
+            position: 0,
+        };
+        // The first frame says the channels and sample rate.
+        let header = source.next_frame(0).ok_or("no WavPack audio")?;
+        let (channels, rate) = source.read_frame(header)?;
+        source.channels = channels as u16;
+        source.sample_rate = rate.ok_or("no WavPack sample rate")?;
+        source.duration = header
+            .total_samples
+            .map(|frames| frames_to_duration(frames, source.sample_rate));
+        let first = duration_to_frames(start, source.sample_rate);
+        let end = header.block_index + header.block_samples as u64;
+        if first < end {
+            source.position =
+                first.saturating_sub(header.block_index) as usize * channels;
+        } else {
+            source.buffer.clear();
+            source.fill(first);
+        }
+        return Ok(source);
+    }
+
+    // Decode the frame holding the sample `first` frames into the song, or
+    // the next one if that has gone by. False at the end of the file.
+    fn fill(&mut self, first: u64) -> bool {
+        let header = match self.next_frame(first) {
+            Some(header) => header,
+            None => return false,
+        };
+        match self.read_frame(header) {
+            Ok((channels, _)) if channels == self.channels as usize => {
+                let skipped = first.saturating_sub(header.block_index);
+                self.position = skipped as usize * channels;
+                return true;
+            }
+            _ => return false,
+        }
+    }
+
+    // The header of the first block of the next frame that reaches past
+    // `first`, skipping the rest without decoding them.
+    fn next_frame(&mut self, first: u64) -> Option<Header> {
+        loop {
+            let header = self.next_header()?;
+            let end = header.block_index + header.block_samples as u64;
+            if header.block_samples > 0 && header.is_initial() && end > first {
+                return Some(header);
+            }
+            self.file.seek_relative(header.size as i64).ok()?;
+        }
+    }
+
+    // Anything that isn't a block, like tags at the end of the file, is
+    // passed over a byte at a time.
+    fn next_header(&mut self) -> Option<Header> {
+        let mut bytes = [0; wavpack::HEADER_LEN];
+        self.file.read_exact(&mut bytes[..4]).ok()?;
+        loop {
+            if &bytes[..4] == b"wvpk" {
+                self.file.read_exact(&mut bytes[4..]).ok()?;
+                if let Some(header) = Header::parse(&bytes) {
+                    return Some(header);
+                }
+                let back = wavpack::HEADER_LEN as i64 - 4;
+                self.file.seek_relative(-back).ok()?;
+            }
+            bytes.copy_within(1..4, 0);
+            self.file.read_exact(&mut bytes[3..4]).ok()?;
+        }
+    }
+
+    // Decode the blocks of the frame starting with `header` into the
+    // buffer, giving the channels and sample rate it has.
+    fn read_frame(
+        &mut self,
+        mut header: Header,
+    ) -> Result<(usize, Option<u32>), String> {
+        let frames = header.block_samples as usize;
+        let mut blocks = Vec::new();
+        loop {
+            let mut body = vec![0; header.size as usize];
+            self.file
+                .read_exact(&mut body)
+                .map_err(|error| error.to_string())?;
+            let block = wavpack::decode_block(&header, &body)?;
+            if block.samples.len() != frames * block.channels {
+                return Err("WavPack blocks don't line up".to_string());
+            }
+            blocks.push(block);
+            if header.is_final() {
+                break;
+            }
+            header = self.next_header().ok_or("WavPack file cut short")?;
+        }
+        let channels: usize = blocks.iter().map(|block| block.channels).sum();
+        self.buffer.clear();
+        self.buffer.resize(frames * channels, 0);
+        let mut offset = 0;
+        for block in blocks.iter() {
+            for (frame, samples) in
+                block.samples.chunks(block.channels).enumerate()
+            {
+                let at = frame * channels + offset;
+                self.buffer[at..at + block.channels].copy_from_slice(samples);
+            }
+            offset += block.channels;
+        }
+        self.position = 0;
+        return Ok((channels, blocks[0].sample_rate));
+    }
+}
+
+impl Iterator for WavPackSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        while self.position >= self.buffer.len() {
+            if !self.fill(0) {
+                return None;
+            }
+        }
+        let sample = self.buffer[self.position];
+        self.position += 1;
+        return Some(sample);
+    }
+}
+
+impl Source for WavPackSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        return None;
+    }
+
+    fn channels(&self) -> u16 {
+        return self.channels;
+    }
+
+    fn sample_rate(&self) -> u32 {
+        return self.sample_rate;
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        return self.duration;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_itunes_gapless_info() {
+        let value = " 00000000 00000840 000001CA 00000000003F31F6 00000000 \
+                     00000000 00000000 00000000 00000000 00000000 00000000 \
+                     00000000";
+        assert_eq!(parse_itunsmpb(value), Some((0x840, 0x3F31F6)));
+        assert_eq!(parse_itunsmpb("00000000 00000840"), None);
+        assert_eq!(parse_itunsmpb("not hex at all"), None);
+    }
+
+    #[test]
+    fn counts_opus_packet_samples() {
+        // One 20ms CELT frame.
+        assert_eq!(opus_packet_frames(&[31 << 3]), 960);
+        // Two 60ms SILK frames.
+        assert_eq!(opus_packet_frames(&[(3 << 3) | 1]), 5760);
+        // Six 2.5ms CELT frames, counted in the second byte.
+        assert_eq!(opus_packet_frames(&[(16 << 3) | 3, 6]), 720);
+        assert_eq!(opus_packet_frames(&[]), 0);
+    }
+}
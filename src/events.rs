@@ -0,0 +1,131 @@
+use std::io::stdin;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use termion::event::Key;
+use termion::input::TermRead;
+
+use crate::library::SharedLibrary;
+use crate::metadata::{self, LibraryChange};
+
+// Everything the main loop reacts to. Each source of events runs on its own
+// thread and sends into the same channel.
+pub enum Event {
+    Key(Key),
+    // Sent once a second so time based parts of the UI stay current.
+    Tick,
+    // Playback went on to the preloaded song with this queue index.
+    TrackChanged(usize),
+    // Playback reached the end with no song to go on to.
+    TrackFinished,
+    // A scan of the music dir finished and the library has its songs.
+    LibraryScanned,
+    // Files in the music dir were added, changed or removed, and the
+    // library has caught up with them.
+    LibraryChanged,
+}
+
+pub struct Events {
+    sender: Sender<Event>,
+    receiver: Receiver<Event>,
+}
+
+impl Events {
+    // Start the input and timer threads.
+    pub fn new() -> Events {
+        let (sender, receiver) = channel();
+        spawn_input_thread(sender.clone());
+        spawn_timer(sender.clone(), Duration::from_secs(1));
+        return Events {
+            sender: sender,
+            receiver: receiver,
+        };
+    }
+
+    // For other threads that want to feed the main loop.
+    pub fn sender(&self) -> Sender<Event> {
+        return self.sender.clone();
+    }
+
+    // Keep `library` up to date with changes to `root` until the returned
+    // watcher is dropped. None if it can't be watched, e.g. when out of
+    // inotify watches.
+    pub fn watch_library(
+        &self,
+        root: &Path,
+        library: SharedLibrary,
+    ) -> Option<RecommendedWatcher> {
+        let (sender, receiver) = channel();
+        let mut watcher: RecommendedWatcher =
+            Watcher::new(sender, Duration::from_secs(2)).ok()?;
+        watcher.watch(root, RecursiveMode::Recursive).ok()?;
+        let events = self.sender();
+        thread::spawn(move || {
+            while let Ok(event) = receiver.recv() {
+                let mut changes: Vec<LibraryChange> =
+                    library_change(event).into_iter().collect();
+                // Files tend to arrive together, e.g. a whole album, and
+                // each batch rebuilds the library.
+                while let Ok(event) =
+                    receiver.recv_timeout(Duration::from_millis(500))
+                {
+                    changes.extend(library_change(event));
+                }
+                if changes.is_empty() {
+                    continue;
+                }
+                if !metadata::apply_changes(&library, changes) {
+                    continue;
+                }
+                if events.send(Event::LibraryChanged).is_err() {
+                    return;
+                }
+            }
+        });
+        return Some(watcher);
+    }
+
+    // Block until the next event arrives.
+    pub fn next(&self) -> Event {
+        // We hold a sender ourselves, so the channel never disconnects.
+        return self.receiver.recv().unwrap();
+    }
+}
+
+fn spawn_input_thread(sender: Sender<Event>) {
+    thread::spawn(move || {
+        for key in stdin().keys().flatten() {
+            if sender.send(Event::Key(key)).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+fn spawn_timer(sender: Sender<Event>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if sender.send(Event::Tick).is_err() {
+            return;
+        }
+    });
+}
+
+fn library_change(event: DebouncedEvent) -> Option<LibraryChange> {
+    match event {
+        DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => {
+            return Some(LibraryChange::Updated(path));
+        }
+        DebouncedEvent::Remove(path) => {
+            return Some(LibraryChange::Removed(path));
+        }
+        DebouncedEvent::Rename(from, to) => {
+            return Some(LibraryChange::Renamed(from, to));
+        }
+        DebouncedEvent::Rescan => return Some(LibraryChange::Rescan),
+        _ => return None,
+    }
+}
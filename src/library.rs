@@ -0,0 +1,478 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use crate::metadata::{self, Album, Artist, LibraryChange, Song, SongId};
+
+// The library as shared between the UI, the scanner and the watcher.
+pub type SharedLibrary = Arc<RwLock<Library>>;
+
+// An album title and year. Songs can only be on an album with theirs, so
+// when songs change only the albums of their titles are built again.
+type Title = (String, u32);
+
+// Every song in the music dir, with the albums and artists they make up.
+// Albums refer to songs by ID and artists to albums by index. Changing a few
+// songs builds their albums again and fixes up those albums' artists, the
+// rest are left as they are.
+pub struct Library {
+    songs: HashMap<SongId, Song>,
+    albums: Vec<Album>,
+    artists: Vec<Artist>,
+    // Album each song is on, as an index into `albums`.
+    song_albums: HashMap<SongId, usize>,
+    // Songs of each album title and year.
+    titles: HashMap<Title, HashSet<SongId>>,
+    // What compilations are listed under.
+    various_artists: String,
+    // Changes to the music dir seen while a scan runs, which would be lost
+    // when its songs replace these. None when no scan is running.
+    deferred: Option<Vec<LibraryChange>>,
+}
+
+impl Library {
+    pub fn new(songs: Vec<Song>, various_artists: String) -> Library {
+        let mut library = Library {
+            songs: HashMap::new(),
+            albums: Vec::new(),
+            artists: Vec::new(),
+            song_albums: HashMap::new(),
+            titles: HashMap::new(),
+            various_artists: various_artists,
+            deferred: None,
+        };
+        library.replace(songs);
+        return library;
+    }
+
+    pub fn shared(self) -> SharedLibrary {
+        return Arc::new(RwLock::new(self));
+    }
+
+    pub fn song(&self, id: SongId) -> Option<&Song> {
+        return self.songs.get(&id);
+    }
+
+    // In no particular order.
+    pub fn songs(&self) -> impl Iterator<Item = &Song> {
+        return self.songs.values();
+    }
+
+    // Copies of all the songs, ordered by path, e.g. for the cache.
+    pub fn to_vec(&self) -> Vec<Song> {
+        let mut songs: Vec<Song> = self.songs.values().cloned().collect();
+        songs.sort_by(|a, b| a.path.cmp(&b.path));
+        return songs;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.songs.len();
+    }
+
+    // In no particular order, as albums come and go one at a time. Each
+    // artist lists theirs alphabetically.
+    pub fn albums(&self) -> &Vec<Album> {
+        return &self.albums;
+    }
+
+    // Alphabetically by name.
+    pub fn artists(&self) -> &Vec<Artist> {
+        return &self.artists;
+    }
+
+    // Index of the artist called `name`.
+    pub fn artist_index(&self, name: &str) -> Option<usize> {
+        return artist_position(&self.artists, name).ok();
+    }
+
+    // Index of the album the song is on.
+    pub fn album_of(&self, id: SongId) -> Option<usize> {
+        return self.song_albums.get(&id).cloned();
+    }
+
+    // Songs `wanted` is true for, ordered by path.
+    pub fn find<F>(&self, wanted: F) -> Vec<&Song>
+    where
+        F: Fn(&Song) -> bool,
+    {
+        let mut songs: Vec<&Song> =
+            self.songs.values().filter(|song| wanted(song)).collect();
+        songs.sort_by(|a, b| a.path.cmp(&b.path));
+        return songs;
+    }
+
+    // Songs of `album` in track order.
+    pub fn album_songs(&self, album: &Album) -> Vec<&Song> {
+        return album.songs.iter().filter_map(|&id| self.song(id)).collect();
+    }
+
+    // Add a song, or replace the one with the same ID.
+    pub fn insert(&mut self, song: Song) {
+        self.swap_songs(&[], vec![song]);
+    }
+
+    // Replace a song already in the library, e.g. after it was re-tagged.
+    // False if there is no such song.
+    pub fn update(&mut self, song: Song) -> bool {
+        if !self.songs.contains_key(&song.id) {
+            return false;
+        }
+        self.insert(song);
+        return true;
+    }
+
+    pub fn remove(&mut self, id: SongId) -> Option<Song> {
+        return self.swap_songs(&[id], Vec::new()).pop();
+    }
+
+    // Insert many songs at once.
+    pub fn extend(&mut self, songs: Vec<Song>) {
+        self.swap_songs(&[], songs);
+    }
+
+    // Keep only the songs `keep` is true for.
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&Song) -> bool,
+    {
+        let removed: Vec<SongId> = self
+            .songs
+            .values()
+            .filter(|song| !keep(song))
+            .map(|song| song.id)
+            .collect();
+        if !removed.is_empty() {
+            self.swap_songs(&removed, Vec::new());
+        }
+    }
+
+    // Take out the songs with the `removed` IDs and put in `added`, which
+    // replace any songs with the same IDs, giving back the songs taken out.
+    // Only the albums these songs were or are now on are built again.
+    pub fn swap_songs(
+        &mut self,
+        removed: &[SongId],
+        added: Vec<Song>,
+    ) -> Vec<Song> {
+        let mut titles: HashSet<Title> = HashSet::new();
+        let replaced = added.iter().map(|song| &song.id);
+        for id in removed.iter().chain(replaced) {
+            if let Some(song) = self.songs.get(id) {
+                titles.insert(title_of(song));
+            }
+        }
+        for song in added.iter() {
+            titles.insert(title_of(song));
+        }
+        // Every album of those titles, from the back so taking one out never
+        // moves another of them.
+        let mut stale: Vec<usize> = titles
+            .iter()
+            .filter_map(|title| self.titles.get(title))
+            .flatten()
+            .filter_map(|id| self.song_albums.get(id).cloned())
+            .collect();
+        stale.sort();
+        stale.dedup();
+
+        let mut taken = Vec::new();
+        for id in removed {
+            if let Some(song) = self.take_song(*id) {
+                taken.push(song);
+            }
+        }
+        for song in added {
+            self.take_song(song.id);
+            self.titles
+                .entry(title_of(&song))
+                .or_insert(HashSet::new())
+                .insert(song.id);
+            self.songs.insert(song.id, song);
+        }
+
+        for &index in stale.iter().rev() {
+            self.remove_album(index);
+        }
+        for title in titles {
+            let songs = match self.titles.get(&title) {
+                Some(ids) => ids.iter().filter_map(|id| self.songs.get(id)),
+                None => continue,
+            };
+            let albums = metadata::init_albums(songs, &self.various_artists);
+            for album in albums {
+                self.add_album(album);
+            }
+        }
+        return taken;
+    }
+
+    // Swap every song for `songs`, as after a full scan. Everything is built
+    // again in one go, which is quicker than a song at a time.
+    pub fn replace(&mut self, songs: Vec<Song>) {
+        self.songs = songs.into_iter().map(|song| (song.id, song)).collect();
+        self.titles.clear();
+        for song in self.songs.values() {
+            self.titles
+                .entry(title_of(song))
+                .or_insert(HashSet::new())
+                .insert(song.id);
+        }
+        self.albums =
+            metadata::init_albums(self.songs.values(), &self.various_artists);
+        self.artists = metadata::init_artists(&self.albums);
+        self.song_albums.clear();
+        for (index, album) in self.albums.iter().enumerate() {
+            for &id in album.songs.iter() {
+                self.song_albums.insert(id, index);
+            }
+        }
+    }
+
+    // Hold on to changes from now on, until the scan that is starting puts
+    // its songs in with `finish_scan`.
+    pub fn start_scan(&mut self) {
+        self.deferred = Some(Vec::new());
+    }
+
+    // Keep `changes` for after the running scan. They are given back if no
+    // scan is running, to be applied now.
+    pub fn defer(
+        &mut self,
+        changes: Vec<LibraryChange>,
+    ) -> Option<Vec<LibraryChange>> {
+        match self.deferred {
+            Some(ref mut deferred) => {
+                deferred.extend(changes);
+                return None;
+            }
+            None => return Some(changes),
+        }
+    }
+
+    // Put in the songs a scan found, giving back the changes seen while it
+    // ran to be applied on top.
+    pub fn finish_scan(&mut self, songs: Vec<Song>) -> Vec<LibraryChange> {
+        self.replace(songs);
+        return self.deferred.take().unwrap_or_default();
+    }
+
+    // Take a song out of the songs and titles, leaving its album be.
+    fn take_song(&mut self, id: SongId) -> Option<Song> {
+        let song = self.songs.remove(&id)?;
+        let title = title_of(&song);
+        if let Some(ids) = self.titles.get_mut(&title) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.titles.remove(&title);
+            }
+        }
+        return Some(song);
+    }
+
+    // Take out an album and its artists' entries for it. The last album is
+    // moved into its place, so that one's entries are pointed at its new
+    // index.
+    fn remove_album(&mut self, index: usize) {
+        let album = self.albums.swap_remove(index);
+        for id in album.songs.iter() {
+            self.song_albums.remove(id);
+        }
+        for name in album.artists.iter().chain(album.track_artists.iter()) {
+            if let Ok(position) = artist_position(&self.artists, name) {
+                let albums = &mut self.artists[position].albums;
+                albums.retain(|&listed| listed != index);
+                if albums.is_empty() {
+                    self.artists.remove(position);
+                }
+            }
+        }
+
+        let last = self.albums.len();
+        if index == last {
+            return;
+        }
+        let moved = &self.albums[index];
+        for &id in moved.songs.iter() {
+            self.song_albums.insert(id, index);
+        }
+        for name in moved.artists.iter().chain(moved.track_artists.iter()) {
+            if let Ok(position) = artist_position(&self.artists, name) {
+                for listed in self.artists[position].albums.iter_mut() {
+                    if *listed == last {
+                        *listed = index;
+                    }
+                }
+            }
+        }
+    }
+
+    // Add an album at the end and list it under its artists, adding any
+    // that are new.
+    fn add_album(&mut self, album: Album) {
+        let index = self.albums.len();
+        for &id in album.songs.iter() {
+            self.song_albums.insert(id, index);
+        }
+        for name in album.artists.iter().chain(album.track_artists.iter()) {
+            let position = match artist_position(&self.artists, name) {
+                Ok(position) => position,
+                Err(position) => {
+                    let artist = Artist {
+                        albums: Vec::new(),
+                        name: name.clone(),
+                    };
+                    self.artists.insert(position, artist);
+                    position
+                }
+            };
+            let albums = &self.albums;
+            let listed = &mut self.artists[position].albums;
+            // An artist of a compilation can be on it twice.
+            if listed.contains(&index) {
+                continue;
+            }
+            let at = listed.partition_point(|&other| {
+                album_order(&albums[other]) < album_order(&album)
+            });
+            listed.insert(at, index);
+        }
+        self.albums.push(album);
+    }
+}
+
+fn title_of(song: &Song) -> Title {
+    return (song.album.clone(), song.year);
+}
+
+fn artist_position(artists: &[Artist], name: &str) -> Result<usize, usize> {
+    return artists.binary_search_by(|artist| (*artist.name).cmp(name));
+}
+
+// Alphabetically, with albums of the same title oldest first, as
+// `init_albums` sorts them.
+fn album_order(album: &Album) -> (&str, u32, &Vec<Arc<str>>) {
+    return (&album.title, album.year, &album.artists);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::test_song;
+    use std::time::{Duration, Instant};
+
+    fn song(artist: &str, album: &str, track: u32, compilation: bool) -> Song {
+        let path = format!("/music/{}/{:02}.flac", album, track);
+        let title = format!("Track {}", track);
+        let mut song = test_song(artist, album, &title, &path);
+        song.track = track;
+        song.compilation = compilation;
+        return song;
+    }
+
+    // 4,000 artists with five albums of ten songs each, one album in fifty
+    // being a compilation with a different artist on every song.
+    fn synthetic_library() -> Vec<Song> {
+        let mut songs = Vec::with_capacity(200_000);
+        for artist in 0..4000 {
+            for album in 0..5 {
+                let compilation = (artist * 5 + album) % 50 == 0;
+                for track in 1..=10 {
+                    let name = if compilation {
+                        format!("Artist {}", (artist + track as usize) % 4000)
+                    } else {
+                        format!("Artist {}", artist)
+                    };
+                    let title = format!("Album {}-{}", artist, album);
+                    songs.push(song(&name, &title, track, compilation));
+                }
+            }
+        }
+        return songs;
+    }
+
+    // Each artist with the albums listed under them, in order.
+    fn listing(library: &Library) -> Vec<(String, Vec<(String, Vec<SongId>)>)> {
+        return library
+            .artists()
+            .iter()
+            .map(|artist| {
+                let albums = artist
+                    .albums
+                    .iter()
+                    .map(|&index| &library.albums()[index])
+                    .map(|album| (album.title.to_string(), album.songs.clone()))
+                    .collect();
+                (artist.name.to_string(), albums)
+            })
+            .collect();
+    }
+
+    #[test]
+    fn changes_only_affected_albums() {
+        let songs = synthetic_library()[..2000].to_vec();
+        let mut library =
+            Library::new(songs.clone(), "Various Artists".to_string());
+
+        // Retag a compilation's songs to one artist, take an album out and
+        // add a new one.
+        let retagged: Vec<Song> = songs[..10]
+            .iter()
+            .cloned()
+            .map(|mut song| {
+                song.artist = "Someone Else".to_string();
+                song
+            })
+            .collect();
+        let removed: Vec<SongId> =
+            songs[10..20].iter().map(|song| song.id).collect();
+        let added: Vec<Song> = (1..=3)
+            .map(|track| song("New Artist", "New Album", track, false))
+            .collect();
+        let mut changed = retagged.clone();
+        changed.extend(added.clone());
+        let taken = library.swap_songs(&removed, changed);
+        assert_eq!(taken.len(), 10);
+
+        let mut expected = songs[20..].to_vec();
+        expected.extend(retagged);
+        expected.extend(added);
+        let rebuilt = Library::new(expected, "Various Artists".to_string());
+        assert_eq!(library.albums().len(), rebuilt.albums().len());
+        assert_eq!(listing(&library), listing(&rebuilt));
+        for (index, album) in library.albums().iter().enumerate() {
+            for &id in album.songs.iter() {
+                assert_eq!(library.album_of(id), Some(index));
+            }
+        }
+    }
+
+    #[test]
+    fn builds_large_library() {
+        let songs = synthetic_library();
+        assert_eq!(songs.len(), 200_000);
+
+        let library = Library::new(songs, "Various Artists".to_string());
+
+        assert_eq!(library.len(), 200_000);
+        assert_eq!(library.albums().len(), 20_000);
+        // Every artist plus the compilations' entry.
+        assert_eq!(library.artists().len(), 4001);
+        let various = library.artist_index("Various Artists").unwrap();
+        assert_eq!(library.artists()[various].albums.len(), 400);
+    }
+
+    // Timing depends on the machine, so the budget is far above what a
+    // debug build needs and only catches building going quadratic again.
+    #[test]
+    fn builds_large_library_quickly() {
+        let songs = synthetic_library();
+        let start = Instant::now();
+        let library = Library::new(songs, "Various Artists".to_string());
+        let elapsed = start.elapsed();
+        assert_eq!(library.len(), 200_000);
+        assert!(
+            elapsed < Duration::from_secs(60),
+            "building took {:?}",
+            elapsed
+        );
+    }
+}
@@ -1,33 +1,55 @@
 use std::fs::File;
-use std::io::{stdin, stdout, BufReader, Write};
-use std::thread::sleep;
+use std::io::{stdout, Write};
+use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 
-use termion::input::TermRead;
 use termion::raw::IntoRawMode;
 
-use rodio::Sink;
-
 use simplelog::*;
 
 pub mod panes;
-use crate::panes::Pane;
+use crate::panes::{Pane, QueuePane, ReportPane, SearchPane};
 
 pub mod metadata;
-use crate::metadata::{Album, Artist, Song};
+use crate::metadata::{ScanProgress, ScanReport, Song};
 
 pub mod config;
 
+pub mod queue;
+use crate::queue::Queue;
+
+pub mod player;
+use crate::player::Player;
+
+pub mod events;
+use crate::events::{Event, Events};
+
+pub mod session;
+use crate::session::Session;
+
+pub mod decode;
+pub mod library;
+use crate::library::{Library, SharedLibrary};
+pub mod query;
+pub mod search;
+pub mod wavpack;
+
 #[macro_use]
 extern crate serde_derive;
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        run_command(&args);
+        return ();
+    }
+
     let _ = WriteLogger::init(
         LevelFilter::Info,
         Config::default(),
         File::create("my_rust_bin.log").unwrap(),
     );
-    let stdin = stdin();
     let mut stdout = stdout().into_raw_mode().unwrap();
 
     let mut size = refresh_size();
@@ -41,58 +63,437 @@ fn main() {
     )
     .unwrap();
 
-    let mut songs = metadata::init_songs();
-    let mut albums = metadata::init_albums(&songs);
-    let mut artists = metadata::init_artists(&songs, &albums);
-
+    let mut ui_state = UiState::AlbumArtistView;
     let mut focused_pane = FocusedPane::Pane1;
+    // Where the panes were, kept while they are built again.
+    let mut cursors = Vec::new();
+    let mut queue_pane = QueuePane::new();
+    let mut report_pane = ReportPane::new();
 
-    let mut artist_pane = Pane::init_artist_pane(&artists, &albums, size);
+    let config = config::Config::from_config_file();
+    let mut search_pane = SearchPane::new(config.playlists.clone());
+    let mut player = Player::new(Duration::from_secs(config.crossfade_secs));
+    let mut queue = Queue::new();
 
-    artist_pane.draw(&mut stdout, &focused_pane, size);
-    stdout.flush().unwrap();
+    if let Some(session) = Session::load() {
+        session.restore(
+            &mut queue,
+            &mut player,
+            &mut focused_pane,
+            &mut cursors,
+        );
+    }
 
-    let device = rodio::default_output_device().unwrap();
-    let sink = Sink::new(&device);
+    let events = Events::new();
+    player.watch(events.sender());
 
-    let mut stdin = stdin.keys();
+    // Set while the library is being scanned in the background.
+    let mut scanning: Option<Arc<ScanProgress>> = None;
+    let library =
+        Library::new(Vec::new(), config.various_artists.clone()).shared();
+    match metadata::cached_songs() {
+        Some(songs) => library.write().unwrap().replace(songs),
+        None => {
+            let progress = Arc::new(ScanProgress::new());
+            scanning = Some(progress.clone());
+            scan_in_background(&events, &library, move || {
+                metadata::scan_library_dir(progress)
+            });
+        }
+    }
+    let _watcher =
+        events.watch_library(&metadata::music_dir_path(), library.clone());
     loop {
-        size = refresh_size();
-        let event = stdin.next();
-        use termion::event::Key::*;
-        if let Some(Ok(key)) = event {
-            match key {
-                Char('k') | Up => {
-                    move_up(&albums, size, &focused_pane, &mut artist_pane);
-                    artist_pane.draw(&mut stdout, &focused_pane, size);
+        let mut artist_pane =
+            Pane::init_artist_pane(&library.read().unwrap(), size);
+        artist_pane.set_cursors(&cursors, &library.read().unwrap(), size);
+        // Every scan saves a new report.
+        let report = ScanReport::load();
+        search_pane.refresh(&library.read().unwrap());
+
+        match ui_state {
+            UiState::QueueView => queue_pane.draw(&mut stdout, &queue, size),
+            UiState::ReportView => report_pane.draw(&mut stdout, &report, size),
+            UiState::SearchView => {
+                search_pane.draw(&mut stdout, &library.read().unwrap(), size)
+            }
+            _ => artist_pane.draw(&mut stdout, &focused_pane, size),
+        }
+        panes::draw_volume(
+            &mut stdout,
+            player.volume(),
+            player.is_muted(),
+            size,
+        );
+        stdout.flush().unwrap();
+
+        let mut status = String::new();
+        // Runs until the library changes and the panes need building again.
+        loop {
+            size = refresh_size();
+            match events.next() {
+                Event::Key(key) => {
+                    // Any key may have redrawn the panes over the status bar.
+                    status.clear();
+                    use termion::event::Key::*;
+                    let handled = match ui_state {
+                        UiState::QueueView => queue_view_key(
+                            key,
+                            &mut queue_pane,
+                            &mut queue,
+                            &mut player,
+                            size,
+                        ),
+                        UiState::ReportView => report_view_key(
+                            key,
+                            &mut report_pane,
+                            &report,
+                            size,
+                        ),
+                        UiState::SearchView => {
+                            let action = search_view_key(
+                                key,
+                                &mut search_pane,
+                                &library.read().unwrap(),
+                            );
+                            match action {
+                                SearchAction::Nothing => {}
+                                SearchAction::Close => {
+                                    ui_state = UiState::AlbumArtistView;
+                                }
+                                SearchAction::Jump(location) => {
+                                    artist_pane.select(
+                                        &location,
+                                        &library.read().unwrap(),
+                                        size,
+                                    );
+                                    focused_pane = match location.len() {
+                                        1 => FocusedPane::Pane1,
+                                        2 => FocusedPane::Pane2,
+                                        _ => FocusedPane::Pane3,
+                                    };
+                                    ui_state = UiState::AlbumArtistView;
+                                }
+                                SearchAction::Play(songs) => {
+                                    play_songs(&mut player, &mut queue, songs)
+                                }
+                                SearchAction::Append(songs) => {
+                                    enqueue(&mut player, &mut queue, songs)
+                                }
+                            }
+                            if ui_state == UiState::AlbumArtistView {
+                                artist_pane.draw(
+                                    &mut stdout,
+                                    &focused_pane,
+                                    size,
+                                );
+                            }
+                            true
+                        }
+                        _ => false,
+                    };
+                    match key {
+                        _ if handled => {}
+                        Char('\t') => {
+                            if ui_state == UiState::QueueView {
+                                ui_state = UiState::AlbumArtistView;
+                                artist_pane.draw(
+                                    &mut stdout,
+                                    &focused_pane,
+                                    size,
+                                );
+                            } else {
+                                ui_state = UiState::QueueView;
+                                if let Some(index) = queue.position() {
+                                    queue_pane.select(index, size);
+                                }
+                            }
+                        }
+                        Char('E') => {
+                            if ui_state == UiState::ReportView {
+                                ui_state = UiState::AlbumArtistView;
+                                artist_pane.draw(
+                                    &mut stdout,
+                                    &focused_pane,
+                                    size,
+                                );
+                            } else {
+                                ui_state = UiState::ReportView;
+                            }
+                        }
+                        Char('k') | Up => {
+                            move_up(
+                                &library.read().unwrap(),
+                                size,
+                                &focused_pane,
+                                &mut artist_pane,
+                            );
+                            artist_pane.draw(&mut stdout, &focused_pane, size);
+                        }
+                        Char('j') | Down => {
+                            move_down(
+                                &library.read().unwrap(),
+                                size,
+                                &focused_pane,
+                                &mut artist_pane,
+                            );
+                            artist_pane.draw(&mut stdout, &focused_pane, size);
+                        }
+                        Char('l') | Right => {
+                            focused_pane = move_right(&focused_pane);
+                            artist_pane.draw(&mut stdout, &focused_pane, size);
+                        }
+                        Char('h') | Left => {
+                            focused_pane = move_left(&focused_pane);
+                            artist_pane.draw(&mut stdout, &focused_pane, size);
+                        }
+                        Char('\n') | Char(' ') => {
+                            let selected = get_selected_songs(
+                                &library.read().unwrap(),
+                                &focused_pane,
+                                &artist_pane,
+                            );
+                            play_songs(&mut player, &mut queue, selected);
+                        }
+                        Char('a') => {
+                            let selected = get_selected_songs(
+                                &library.read().unwrap(),
+                                &focused_pane,
+                                &artist_pane,
+                            );
+                            enqueue(&mut player, &mut queue, selected);
+                        }
+                        Char('/') => {
+                            ui_state = UiState::SearchView;
+                            search_pane.clear(&library.read().unwrap());
+                        }
+                        Char('>') => {
+                            if queue.next().is_some() {
+                                play_queue(
+                                    &mut player,
+                                    &queue,
+                                    Duration::new(0, 0),
+                                );
+                            }
+                        }
+                        Char('<') => {
+                            if queue.previous().is_some() {
+                                play_queue(
+                                    &mut player,
+                                    &queue,
+                                    Duration::new(0, 0),
+                                );
+                            }
+                        }
+                        Char('c') => {
+                            queue.clear();
+                            player.stop();
+                        }
+                        Char('p') => {
+                            if player.is_stopped() {
+                                play_queue(
+                                    &mut player,
+                                    &queue,
+                                    Duration::new(0, 0),
+                                );
+                            } else {
+                                player.toggle_pause();
+                            }
+                        }
+                        Char('s') => player.stop(),
+                        Char('f') => seek_by(&mut player, &queue, 5),
+                        Char('b') => seek_by(&mut player, &queue, -5),
+                        Char('F') => seek_by(&mut player, &queue, 30),
+                        Char('B') => seek_by(&mut player, &queue, -30),
+                        Char(c) if c.is_ascii_digit() => {
+                            let percent = c.to_digit(10).unwrap() * 10;
+                            seek_to_percent(&mut player, &queue, percent);
+                        }
+                        Char('z') => queue.cycle_shuffle(),
+                        Char('r') => queue.cycle_repeat(),
+                        Char('+') | Char('=') => player.volume_up(),
+                        Char('-') => player.volume_down(),
+                        Char('m') => player.toggle_mute(),
+                        Char('u') if scanning.is_none() => {
+                            let progress = Arc::new(ScanProgress::new());
+                            scanning = Some(progress.clone());
+                            let cached = library.read().unwrap().to_vec();
+                            scan_in_background(&events, &library, move || {
+                                metadata::rescan_library(&cached, progress)
+                            });
+                        }
+                        Char('q') => {
+                            Session::new(
+                                &queue,
+                                &player,
+                                &focused_pane,
+                                &artist_pane,
+                            )
+                            .save();
+                            return ();
+                        }
+                        _ => {}
+                    }
+                    if ui_state == UiState::QueueView {
+                        queue_pane.draw(&mut stdout, &queue, size);
+                    } else if ui_state == UiState::ReportView {
+                        report_pane.draw(&mut stdout, &report, size);
+                    } else if ui_state == UiState::SearchView {
+                        search_pane.draw(
+                            &mut stdout,
+                            &library.read().unwrap(),
+                            size,
+                        );
+                    }
+                    // Keys can change what comes next.
+                    if !player.is_stopped() {
+                        player.preload(queue.peek_advance());
+                    }
+                    panes::draw_volume(
+                        &mut stdout,
+                        player.volume(),
+                        player.is_muted(),
+                        size,
+                    );
                 }
-                Char('j') | Down => {
-                    move_down(&albums, size, &focused_pane, &mut artist_pane);
-                    artist_pane.draw(&mut stdout, &focused_pane, size);
+                Event::Tick => {}
+                Event::TrackChanged(index) => {
+                    queue.set_position(index);
+                    player.preload(queue.peek_advance());
+                    if ui_state == UiState::QueueView {
+                        queue_pane.draw(&mut stdout, &queue, size);
+                    }
                 }
-                Char('l') | Right => {
-                    focused_pane = move_right(&focused_pane);
-                    artist_pane.draw(&mut stdout, &focused_pane, size);
+                Event::LibraryScanned => {
+                    scanning = None;
+                    cursors = artist_pane.get_cursors();
+                    break;
                 }
-                Char('h') | Left => {
-                    focused_pane = move_left(&focused_pane);
-                    artist_pane.draw(&mut stdout, &focused_pane, size);
+                Event::LibraryChanged => {
+                    cursors = artist_pane.get_cursors();
+                    break;
                 }
-                Char('\n') | Char(' ') => {
-                    play_song(&focused_pane, &artist_pane, &songs, &sink);
+                Event::TrackFinished => {
+                    if queue.advance().is_some() {
+                        play_queue(&mut player, &queue, Duration::new(0, 0));
+                    }
+                    if ui_state == UiState::QueueView {
+                        queue_pane.draw(&mut stdout, &queue, size);
+                    }
                 }
-                Char('q') => return (),
-                _ => {}
             }
+            let line = panes::status_line(
+                &player,
+                &queue,
+                scanning.as_deref(),
+                size.0 + 2,
+            );
+            if line != status {
+                panes::draw_status_bar(&mut stdout, &line, size);
+                status = line;
+            }
+            stdout.flush().unwrap();
         }
-        stdout.flush().unwrap();
     }
 }
 
+// Run a scan on its own thread, so the UI stays usable, and put the songs
+// in the library once it is done. Changes the watcher sees meanwhile are
+// applied after.
+fn scan_in_background<F>(events: &Events, library: &SharedLibrary, scan: F)
+where
+    F: FnOnce() -> Vec<Song> + Send + 'static,
+{
+    let sender = events.sender();
+    let library = library.clone();
+    library.write().unwrap().start_scan();
+    thread::spawn(move || {
+        let songs = scan();
+        let missed = library.write().unwrap().finish_scan(songs);
+        if !missed.is_empty() {
+            metadata::apply_changes(&library, missed);
+        }
+        let _ = sender.send(Event::LibraryScanned);
+    });
+}
+
+// Commands that run without the UI, e.g. `rsmus rescan`.
+fn run_command(args: &[String]) {
+    match args[0].as_ref() {
+        "rescan" => {
+            let progress = Arc::new(ScanProgress::new());
+            let songs =
+                metadata::rescan_library(&metadata::init_songs(), progress);
+            println!("{} songs in library", songs.len());
+        }
+        "scan-report" => {
+            let report = ScanReport::load();
+            if report.failures.is_empty() {
+                println!("The last scan read every file.");
+            }
+            for failure in report.failures {
+                println!("{}: {}", failure.path, failure.reason);
+            }
+        }
+        "query" => print_matches(&query_from_args(&args[1..])),
+        "playlist" => {
+            let config = config::Config::from_config_file();
+            match args.get(1) {
+                Some(name) => match config.playlists.get(name) {
+                    Some(text) => print_matches(text),
+                    None => {
+                        eprintln!("no smart playlist called {}", name);
+                        std::process::exit(1);
+                    }
+                },
+                None => {
+                    for name in config.playlists.keys() {
+                        println!("{}", name);
+                    }
+                }
+            }
+        }
+        _ => {
+            eprintln!("unknown command: {}", args[0]);
+            eprintln!(
+                "usage: rsmus [rescan | scan-report | query QUERY | \
+                 playlist [NAME]]"
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+// Print the path of every song the query matches, or what is wrong with it.
+fn print_matches(text: &str) {
+    let query = match query::parse(text) {
+        Ok(query) => query,
+        Err(error) => {
+            eprintln!("{}", text);
+            eprintln!("{}^", " ".repeat(error.position));
+            eprintln!("error: {}", error.message);
+            std::process::exit(1);
+        }
+    };
+    let config = config::Config::from_config_file();
+    let library = Library::new(metadata::init_songs(), config.various_artists);
+    for song in library.find(|song| query.matches(song)) {
+        println!("{}", song.path);
+    }
+}
+
+// The query in a command's arguments, which can be given whole or a term
+// at a time. Values with spaces need their quotes kept from the shell, e.g.
+// `rsmus query 'artist:"boards of canada"' year:1998`.
+fn query_from_args(args: &[String]) -> String {
+    return args.join(" ");
+}
+
 fn refresh_size() -> (u16, u16) {
     let termsize = termion::terminal_size().ok();
     let width = termsize.map(|(w, _)| w - 2).unwrap();
-    let height = termsize.map(|(_, h)| h - 2).unwrap();
+    // Bottom row is kept for the status bar.
+    let height = termsize.map(|(_, h)| h - 3).unwrap();
     return (width, height);
 }
 
@@ -111,87 +512,281 @@ fn move_left(focused_pane: &FocusedPane) -> FocusedPane {
     }
 }
 
-fn move_up<'a, 'b>(
-    albums: &'a Vec<Album>,
+fn move_up(
+    library: &Library,
     size: (u16, u16),
     focused_pane: &FocusedPane,
-    root_pane: &'b mut Pane<'a>,
+    root_pane: &mut Pane,
 ) {
     match focused_pane {
         FocusedPane::Pane1 => {
-            root_pane.move_up(albums, size);
+            root_pane.move_up(library, size);
         }
         FocusedPane::Pane2 => {
-            root_pane.move_child_up(albums, size);
+            root_pane.move_child_up(library, size);
         }
         FocusedPane::Pane3 => match root_pane.child_pane {
-            Some(ref mut pane) => pane.move_child_up(albums, size),
+            Some(ref mut pane) => pane.move_child_up(library, size),
             None => {}
         },
     }
 }
-fn move_down<'a, 'b>(
-    albums: &'a Vec<Album>,
+fn move_down(
+    library: &Library,
     size: (u16, u16),
     focused_pane: &FocusedPane,
-    root_pane: &'b mut Pane<'a>,
+    root_pane: &mut Pane,
 ) {
     match focused_pane {
         FocusedPane::Pane1 => {
-            root_pane.move_down(albums, size);
+            root_pane.move_down(library, size);
         }
         FocusedPane::Pane2 => {
-            root_pane.move_child_down(albums, size);
+            root_pane.move_child_down(library, size);
         }
         FocusedPane::Pane3 => match root_pane.child_pane {
-            Some(ref mut pane) => pane.move_child_down(albums, size),
+            Some(ref mut pane) => pane.move_child_down(library, size),
             None => {}
         },
     }
 }
 
-fn get_selected<'a>(
+// Keys that act on the queue view. Returns false for keys it leaves to the
+// main loop.
+fn queue_view_key(
+    key: termion::event::Key,
+    queue_pane: &mut QueuePane,
+    queue: &mut Queue,
+    player: &mut Player,
+    size: (u16, u16),
+) -> bool {
+    use termion::event::Key::*;
+    let selected = queue_pane.get_selected();
+    match key {
+        Char('k') | Up => queue_pane.move_up(size),
+        Char('j') | Down => queue_pane.move_down(queue, size),
+        // The other panes are hidden, so these have nothing to act on.
+        Char('h') | Left | Char('l') | Right | Char('a') => {}
+        Char('K') => {
+            if queue.move_up(selected) {
+                queue_pane.move_up(size);
+            }
+        }
+        Char('J') => {
+            if queue.move_down(selected) {
+                queue_pane.move_down(queue, size);
+            }
+        }
+        Char('d') => {
+            let was_current = queue.position() == Some(selected);
+            queue.remove(selected);
+            if was_current && !player.is_stopped() {
+                play_queue(player, queue, Duration::new(0, 0));
+            }
+        }
+        Char('\n') | Char(' ') => {
+            queue.set_position(selected);
+            play_queue(player, queue, Duration::new(0, 0));
+        }
+        _ => return false,
+    }
+    // Moving songs about changes the index of the one playing.
+    if let Some(index) = queue.position() {
+        player.set_index(index);
+    }
+    return true;
+}
+
+// Keys that scroll the scan report. Returns false for keys it leaves to the
+// main loop.
+fn report_view_key(
+    key: termion::event::Key,
+    report_pane: &mut ReportPane,
+    report: &ScanReport,
+    size: (u16, u16),
+) -> bool {
+    use termion::event::Key::*;
+    match key {
+        Char('k') | Up => report_pane.scroll_up(),
+        Char('j') | Down => report_pane.scroll_down(report, size),
+        // The library panes are hidden, so these have nothing to act on.
+        Char('h') | Left | Char('l') | Right => {}
+        Char('\n') | Char(' ') | Char('a') => {}
+        _ => return false,
+    }
+    return true;
+}
+
+// What a key in the search view has the main loop do.
+enum SearchAction {
+    Nothing,
+    Close,
+    // Show the result at this location in the library panes.
+    Jump(Vec<usize>),
+    Play(Vec<Song>),
+    Append(Vec<Song>),
+}
+
+// Keys that act on the search view. Every key is taken, as most of them
+// are typed into the query.
+fn search_view_key(
+    key: termion::event::Key,
+    search_pane: &mut SearchPane,
+    library: &Library,
+) -> SearchAction {
+    use termion::event::Key::*;
+    let selected = search_pane.get_selected();
+    match (key, selected) {
+        (Esc, _) => return SearchAction::Close,
+        (Up, _) => search_pane.move_up(),
+        (Down, _) => search_pane.move_down(),
+        (Backspace, _) => search_pane.pop(library),
+        (Char('\n'), Some(result)) => match result.location(library) {
+            Some(location) => return SearchAction::Jump(location),
+            None => {}
+        },
+        (Ctrl('p'), Some(result)) => {
+            return SearchAction::Play(result.songs(library));
+        }
+        (Ctrl('a'), Some(result)) => {
+            return SearchAction::Append(result.songs(library));
+        }
+        (Char('\n'), None) | (Char('\t'), _) => {}
+        (Char(c), _) => search_pane.push(c, library),
+        _ => {}
+    }
+    return SearchAction::Nothing;
+}
+
+// Replace the queue with `songs` and play the first.
+fn play_songs(player: &mut Player, queue: &mut Queue, songs: Vec<Song>) {
+    if !songs.is_empty() {
+        queue.replace(songs, 0);
+        play_queue(player, queue, Duration::new(0, 0));
+    }
+}
+
+// Add `songs` to the end of the queue.
+fn enqueue(player: &mut Player, queue: &mut Queue, songs: Vec<Song>) {
+    let index = queue.len();
+    let added = !songs.is_empty();
+    queue.append(songs);
+    // If nothing is playing, start on the first one.
+    if added && player.is_stopped() {
+        queue.set_position(index);
+        play_queue(player, queue, Duration::new(0, 0));
+    }
+}
+
+// Songs to queue for the current selection. Only tracks in the album view
+// can be queued; the rest of the album follows the highlighted one.
+fn get_selected_songs(
+    library: &Library,
     focused_pane: &FocusedPane,
-    root_pane: &'a Pane,
-) -> &'a str {
+    root_pane: &Pane,
+) -> Vec<Song> {
     match focused_pane {
-        FocusedPane::Pane1 => root_pane.get_selected(),
-        FocusedPane::Pane2 => root_pane.get_child_selected(),
         FocusedPane::Pane3 => match root_pane.child_pane {
-            Some(ref pane) => pane.get_child_selected(),
-            None => return "error",
+            Some(ref pane) => return pane.get_child_selected_songs(library),
+            None => return Vec::new(),
         },
+        _ => return Vec::new(),
     }
 }
 
-fn play_song(
-    focused_pane: &FocusedPane,
-    root_pane: &Pane,
-    songs: &Vec<Song>,
-    sink: &Sink,
-) {
-    if *focused_pane == FocusedPane::Pane3 {
-        let song_title = get_selected(focused_pane, root_pane);
-        let song: &Song = songs
-            .into_iter()
-            .find(|song| song.title == song_title)
-            .unwrap();
+// Play the current song of the queue, with the one after it lined up.
+fn play_queue(player: &mut Player, queue: &Queue, start: Duration) {
+    match (queue.current(), queue.position()) {
+        (Some(song), Some(index)) => {
+            player.play(song, index, start);
+            player.preload(queue.peek_advance());
+        }
+        _ => player.stop(),
+    }
+}
+
+fn seek_by(player: &mut Player, queue: &Queue, secs: i64) {
+    if player.is_stopped() {
+        return;
+    }
+    let elapsed = player.elapsed();
+    let offset = Duration::new(secs.unsigned_abs(), 0);
+    let position = if secs < 0 {
+        elapsed.checked_sub(offset).unwrap_or(Duration::new(0, 0))
+    } else {
+        elapsed + offset
+    };
+    seek(player, queue, position);
+}
 
-        let file = File::open(song.path.clone()).unwrap();
-        let source = rodio::Decoder::new(BufReader::new(file)).unwrap();
-        sink.append(source);
+fn seek_to_percent(player: &mut Player, queue: &Queue, percent: u32) {
+    if player.is_stopped() {
+        return;
     }
+    let duration = match queue.current() {
+        Some(song) => song.duration.unwrap_or(Duration::new(0, 0)),
+        None => return,
+    };
+    seek(player, queue, duration * percent / 100);
+}
+
+// Seek within the current song, never past its end.
+fn seek(player: &mut Player, queue: &Queue, position: Duration) {
+    let (song, index) = match (queue.current(), queue.position()) {
+        (Some(song), Some(index)) => (song, index),
+        _ => return,
+    };
+    let mut position = position;
+    if let Some(duration) = song.duration {
+        if position > duration {
+            position = duration;
+        }
+    }
+    player.seek(song, index, position);
 }
 
 #[derive(PartialEq)]
 pub enum UiState {
     AlbumArtistView,
+    QueueView,
     SearchView,
+    ReportView,
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub enum FocusedPane {
     Pane1,
     Pane2,
     Pane3,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::test_song;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        return args.iter().map(|arg| arg.to_string()).collect();
+    }
+
+    #[test]
+    fn reads_query_given_whole() {
+        let text = query_from_args(&args(&["artist:air year:1998"]));
+        assert_eq!(text, "artist:air year:1998");
+        let mut song = test_song("Air", "Moon Safari", "Talisman", "/m/a.mp3");
+        song.year = 1998;
+        assert!(query::parse(&text).unwrap().matches(&song));
+    }
+
+    #[test]
+    fn reads_query_given_by_term() {
+        let text = query_from_args(&args(&[
+            "artist:\"boards of canada\"",
+            "year:1995..2005",
+        ]));
+        assert_eq!(text, "artist:\"boards of canada\" year:1995..2005");
+        let song =
+            test_song("Boards of Canada", "Geogaddi", "1969", "/m/b.mp3");
+        assert!(query::parse(&text).unwrap().matches(&song));
+    }
+}
@@ -1,33 +1,487 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::io::{BufReader, Read, Stdin, Stdout, Write};
+use std::io::{BufReader, Read, Seek, SeekFrom, Stdin, Stdout, Write};
 use std::path::Path;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
 use std::vec::Vec;
 
+use lofty::file::{FileType, TaggedFile};
+use lofty::prelude::{Accessor, AudioFile, ItemKey, TaggedFileExt};
+use lofty::probe::Probe;
 use walkdir::DirEntry;
 use walkdir::WalkDir;
 
 use termion::raw::RawTerminal;
 
 use crate::config;
+use crate::library::SharedLibrary;
 use crate::panes;
-use bincode::{deserialize, serialize};
+use bincode::{deserialize, deserialize_from, serialize, serialize_into};
+
+// Start of every cache file, so anything else is recognised.
+const CACHE_MAGIC: [u8; 4] = *b"RSMC";
+// Bump whenever `Song` changes, adding a migration from the old version
+// where possible.
+// Before version 3 songs lacked the album artist, compilation flag and disc
+// numbers, which can only come from the files, so those caches (including
+// ones without a header) are scanned again.
+const CACHE_VERSION: u32 = 4;
+
+// Written ahead of the songs in the cache.
+#[derive(Serialize, Deserialize)]
+struct CacheHeader {
+    magic: [u8; 4],
+    version: u32,
+    // Music dir the songs were scanned from.
+    library_root: PathBuf,
+    written: SystemTime,
+}
 
 pub fn init_songs() -> Vec<Song> {
-    let mut data_path: PathBuf = dirs::config_dir().unwrap();
-    data_path.push("rsmus/metadata.bin");
-    if data_path.exists() {
-        return metadata_from_binary(data_path);
-    } else {
-        return scan_library_dir();
+    match cached_songs() {
+        Some(songs) => return songs,
+        None => return scan_library_dir(Arc::new(ScanProgress::new())),
+    }
+}
+
+// Songs from the last scan. None if the cache is missing, unreadable or for
+// another music dir, and the library needs scanning.
+pub fn cached_songs() -> Option<Vec<Song>> {
+    return metadata_from_binary(cache_path());
+}
+
+// Bring `songs` up to date with the music dir without reading every file
+// again. Tags are only read for files that are new or whose modified time or
+// size changed, songs whose files are gone are dropped, and the cache is
+// rewritten.
+pub fn rescan_library(
+    songs: &Vec<Song>,
+    progress: Arc<ScanProgress>,
+) -> Vec<Song> {
+    let cached: HashMap<&str, &Song> = songs
+        .iter()
+        .map(|song| (song.path.as_ref(), song))
+        .collect();
+    let mut file_data = Vec::new();
+    let mut failures = Vec::new();
+    let mut changed = Vec::new();
+    let extensions = enabled_extensions();
+    for entry in WalkDir::new(music_dir_path()) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                failures.push(ScanFailure::from_walk(error));
+                continue;
+            }
+        };
+        if !is_audio_file(entry.path(), &extensions) {
+            continue;
+        }
+        let (modified, size) = file_stamp(&entry);
+        let path = entry.path().to_string_lossy();
+        match cached.get(path.as_ref()) {
+            Some(song) if song.modified == modified && song.size == size => {
+                file_data.push((*song).clone());
+            }
+            _ => changed.push(Ok(entry)),
+        }
     }
+    let (read, read_failures) = read_entries(changed.into_iter(), progress);
+    file_data.extend(read);
+    failures.extend(read_failures);
+    write_cache(&file_data);
+    ScanReport::new(failures).save();
+    return file_data;
 }
 
-fn scan_library_dir() -> Vec<Song> {
+pub fn scan_library_dir(progress: Arc<ScanProgress>) -> Vec<Song> {
     // Walk through music dir recursively, getting metadata.
+    let (file_data, failures) = collect_scan(scan_library(progress));
+
+    write_cache(&file_data);
+    ScanReport::new(failures).save();
+
+    //run library_init to init program
+    return file_data;
+}
+
+// How far a scan has got. Shared with the scanning threads, so it can be
+// read while they run.
+pub struct ScanProgress {
+    scanned: AtomicUsize,
+    // Audio files found so far. Grows while the music dir is walked.
+    total: AtomicUsize,
+}
+
+impl ScanProgress {
+    pub fn new() -> ScanProgress {
+        return ScanProgress {
+            scanned: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+        };
+    }
+
+    pub fn scanned(&self) -> usize {
+        return self.scanned.load(Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> usize {
+        return self.total.load(Ordering::Relaxed);
+    }
+
+    // E.g. "scanned 12,345 / 40,000".
+    pub fn status(&self) -> String {
+        return format!(
+            "scanned {} / {}",
+            thousands(self.scanned()),
+            thousands(self.total())
+        );
+    }
+}
+
+fn thousands(number: usize) -> String {
+    let digits = number.to_string();
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    return grouped;
+}
+
+// A file or directory that couldn't be added to the library, and why.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ScanFailure {
+    pub path: String,
+    pub reason: String,
+}
+
+impl ScanFailure {
+    fn new(path: &Path, reason: String) -> ScanFailure {
+        return ScanFailure {
+            path: path.to_string_lossy().to_string(),
+            reason: reason,
+        };
+    }
+
+    fn from_walk(error: walkdir::Error) -> ScanFailure {
+        let path = error.path().unwrap_or(Path::new("")).to_path_buf();
+        let reason = match error.io_error() {
+            Some(io_error) => io_error.to_string(),
+            None => error.to_string(),
+        };
+        return ScanFailure::new(&path, reason);
+    }
+}
+
+// What the last scan couldn't read, so bad files can be found and fixed
+// rather than just missing from the library.
+#[derive(Serialize, Deserialize)]
+pub struct ScanReport {
+    pub failures: Vec<ScanFailure>,
+}
+
+impl ScanReport {
+    fn new(failures: Vec<ScanFailure>) -> ScanReport {
+        let mut failures = failures;
+        failures.sort_by(|a, b| a.path.cmp(&b.path));
+        return ScanReport { failures: failures };
+    }
+
+    // The report saved by the last scan. Empty if there is none.
+    pub fn load() -> ScanReport {
+        let failures = fs::read(report_path())
+            .ok()
+            .and_then(|buffer| deserialize(&buffer[..]).ok())
+            .unwrap_or(Vec::new());
+        return ScanReport { failures: failures };
+    }
+
+    fn save(&self) {
+        let data: Vec<u8> = serialize(&self.failures).unwrap();
+        let report_path = report_path();
+        if let Some(dir) = report_path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        if let Ok(mut report_file) = File::create(report_path) {
+            let _ = report_file.write_all(&data);
+        }
+    }
+}
+
+fn report_path() -> PathBuf {
+    let mut report_path: PathBuf = dirs::config_dir().unwrap();
+    report_path.push("rsmus/scan_report.bin");
+    return report_path;
+}
+
+// Walk the music dir and read every audio file in it, sending songs, or why
+// they couldn't be read, as it goes.
+pub fn scan_library(
+    progress: Arc<ScanProgress>,
+) -> Receiver<Result<Song, ScanFailure>> {
+    return scan_entries(WalkDir::new(music_dir_path()).into_iter(), progress);
+}
+
+// Wait for a scan to finish, keeping what couldn't be read apart.
+fn collect_scan(
+    results: Receiver<Result<Song, ScanFailure>>,
+) -> (Vec<Song>, Vec<ScanFailure>) {
+    let mut songs = Vec::new();
+    let mut failures = Vec::new();
+    for result in results {
+        match result {
+            Ok(song) => songs.push(song),
+            Err(failure) => failures.push(failure),
+        }
+    }
+    return (songs, failures);
+}
+
+// Read tags for the audio files among `entries` on a pool of worker threads,
+// one per core. Workers take the next file off a shared queue as they become
+// free, so a directory of slow files doesn't hold the rest up, and reading
+// starts before the walk is done. Songs, or why they couldn't be read, are
+// sent as they go and the channel closes once every file has been.
+fn scan_entries<I>(
+    entries: I,
+    progress: Arc<ScanProgress>,
+) -> Receiver<Result<Song, ScanFailure>>
+where
+    I: Iterator<Item = walkdir::Result<DirEntry>> + Send + 'static,
+{
+    let (jobs, queue) = channel::<DirEntry>();
+    let queue = Arc::new(Mutex::new(queue));
+    let (sender, results) = channel();
+    let workers = thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(4);
+    for _ in 0..workers {
+        let queue = queue.clone();
+        let sender = sender.clone();
+        let progress = progress.clone();
+        thread::spawn(move || loop {
+            // The queue is only locked while taking the next file.
+            let entry = match queue.lock().unwrap().recv() {
+                Ok(entry) => entry,
+                Err(_) => return,
+            };
+            let result = get_file_metadata(entry);
+            progress.scanned.fetch_add(1, Ordering::Relaxed);
+            if sender.send(result).is_err() {
+                return;
+            }
+        });
+    }
+    let extensions = enabled_extensions();
+    thread::spawn(move || {
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    let _ = sender.send(Err(ScanFailure::from_walk(error)));
+                    continue;
+                }
+            };
+            if !is_audio_file(entry.path(), &extensions) {
+                continue;
+            }
+            progress.total.fetch_add(1, Ordering::Relaxed);
+            if jobs.send(entry).is_err() {
+                return;
+            }
+        }
+    });
+    return results;
+}
+
+// Read tags for `entries` in parallel, waiting for all of them.
+fn read_entries<I>(
+    entries: I,
+    progress: Arc<ScanProgress>,
+) -> (Vec<Song>, Vec<ScanFailure>)
+where
+    I: Iterator<Item = walkdir::Result<DirEntry>> + Send + 'static,
+{
+    return collect_scan(scan_entries(entries, progress));
+}
+
+// Read the files in `paths`, and everything under the ones that are
+// directories, with one pool of workers for all of them.
+fn read_paths(paths: Vec<PathBuf>) -> (Vec<Song>, Vec<ScanFailure>) {
+    let entries = paths
+        .into_iter()
+        .flat_map(|path| WalkDir::new(path).into_iter());
+    return read_entries(entries, Arc::new(ScanProgress::new()));
+}
+
+// A change to the music dir seen while running.
+pub enum LibraryChange {
+    // A file or directory was added or written to.
+    Updated(PathBuf),
+    Removed(PathBuf),
+    Renamed(PathBuf, PathBuf),
+    // Changes were missed, so everything needs checking.
+    Rescan,
+}
+
+// Apply `changes` to the library and save it to the cache. Tags are only
+// read for the files that changed, and without holding the lock. While a
+// scan is running the changes are kept for after it instead, as the cache
+// would only hold part of the library. False if they were kept.
+pub fn apply_changes(
+    library: &SharedLibrary,
+    changes: Vec<LibraryChange>,
+) -> bool {
+    let changes = match library.write().unwrap().defer(changes) {
+        Some(changes) => changes,
+        None => return false,
+    };
+    let mut report = ScanReport::load();
+    // Files written one after another are read again together.
+    let mut updated = Vec::new();
+    for change in changes {
+        if let LibraryChange::Updated(path) = change {
+            updated.push(path);
+            continue;
+        }
+        apply_updates(library, std::mem::take(&mut updated), &mut report);
+        match change {
+            LibraryChange::Updated(_) => {}
+            LibraryChange::Removed(path) => {
+                library
+                    .write()
+                    .unwrap()
+                    .retain(|song| !is_under(&song.path, &path));
+                report
+                    .failures
+                    .retain(|failure| !is_under(&failure.path, &path));
+            }
+            LibraryChange::Renamed(from, to) => {
+                // A song's ID comes from its path, so moved songs are taken
+                // out and put back in under their new one. One renamed to
+                // something that isn't scanned, e.g. "a.mp3.part", is gone.
+                let extensions = enabled_extensions();
+                let mut moved = Vec::new();
+                let mut gone = Vec::new();
+                for song in library.read().unwrap().songs() {
+                    if let Some(new_path) = moved_path(&song.path, &from, &to) {
+                        gone.push(song.id);
+                        if !is_playable(Path::new(&new_path), &extensions) {
+                            continue;
+                        }
+                        let mut song = song.clone();
+                        song.id = song_id(&new_path);
+                        song.path = new_path;
+                        moved.push(song);
+                    }
+                }
+                for failure in report.failures.iter_mut() {
+                    if let Some(new_path) =
+                        moved_path(&failure.path, &from, &to)
+                    {
+                        failure.path = new_path;
+                    }
+                }
+                // Downloads are often written under another name and renamed
+                // once finished.
+                if gone.is_empty() {
+                    let (read, failures) = read_paths(vec![to]);
+                    library.write().unwrap().extend(read);
+                    report.failures.extend(failures);
+                } else {
+                    library.write().unwrap().swap_songs(&gone, moved);
+                }
+            }
+            LibraryChange::Rescan => {
+                let songs = library.read().unwrap().to_vec();
+                let songs =
+                    rescan_library(&songs, Arc::new(ScanProgress::new()));
+                library.write().unwrap().replace(songs);
+                report = ScanReport::load();
+            }
+        }
+    }
+    apply_updates(library, updated, &mut report);
+    write_cache(&library.read().unwrap().to_vec());
+    ScanReport::new(report.failures).save();
+    return true;
+}
+
+// Read the files and directories in `paths` again and swap them in for the
+// songs that were there.
+fn apply_updates(
+    library: &SharedLibrary,
+    paths: Vec<PathBuf>,
+    report: &mut ScanReport,
+) {
+    if paths.is_empty() {
+        return;
+    }
+    let roots = outermost(paths);
+    let (read, failures) = read_paths(roots.clone());
+    let root_set: HashSet<&Path> = roots.iter().map(|root| &**root).collect();
+    let under_roots = |path: &str| {
+        return Path::new(path)
+            .ancestors()
+            .any(|dir| root_set.contains(dir));
+    };
+    // Looked for under a read lock, so the UI isn't held up.
+    let stale: Vec<SongId> = library
+        .read()
+        .unwrap()
+        .songs()
+        .filter(|song| under_roots(&song.path))
+        .map(|song| song.id)
+        .collect();
+    report
+        .failures
+        .retain(|failure| !under_roots(&failure.path));
+    library.write().unwrap().swap_songs(&stale, read);
+    report.failures.extend(failures);
+}
+
+// `paths` without the ones under another of them, which would otherwise be
+// read twice.
+fn outermost(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut paths = paths;
+    // Everything under a directory sorts right after it.
+    paths.sort();
+    let mut roots: Vec<PathBuf> = Vec::new();
+    for path in paths {
+        match roots.last() {
+            Some(root) if path.starts_with(root) => {}
+            _ => roots.push(path),
+        }
+    }
+    return roots;
+}
+
+fn is_under(path: &str, root: &Path) -> bool {
+    return Path::new(path).starts_with(root);
+}
+
+// Where `path` ends up when `from` is renamed to `to`, if it's under `from`.
+fn moved_path(path: &str, from: &Path, to: &Path) -> Option<String> {
+    let rest = Path::new(path).strip_prefix(from).ok()?;
+    // The file itself was renamed. Joining nothing would add a slash.
+    if rest.as_os_str().is_empty() {
+        return Some(to.to_string_lossy().to_string());
+    }
+    return Some(to.join(rest).to_string_lossy().to_string());
+}
+
+pub fn music_dir_path() -> PathBuf {
     let config = config::Config::from_config_file();
-    let mut file_data = Vec::new();
     let mut music_path = PathBuf::new();
     let music_path_str = &config.music_dir;
     if music_path_str.starts_with("~/") {
@@ -37,157 +491,508 @@ fn scan_library_dir() -> Vec<Song> {
     } else {
         music_path.push(music_path_str);
     }
+    return music_path;
+}
 
-    let entries: Vec<DirEntry> = WalkDir::new(music_path)
-        .into_iter()
-        .map(|a| a.unwrap())
-        .collect();
-
-    // Send data to threads for (theoretically) faster processing.
-    // Needs work.
-    let mut slices = entries.chunks(entries.len() / 4);
-    let slice1 = slices.next().unwrap().to_vec();
-    let slice2 = slices.next().unwrap().to_vec();
-    let slice3 = slices.next().unwrap().to_vec();
-    let slice4 = slices.next().unwrap().to_vec();
-    let data1 = std::thread::spawn(move || thread_closure(slice1));
-    let data2 = std::thread::spawn(move || thread_closure(slice2));
-    let data3 = std::thread::spawn(move || thread_closure(slice3));
-    let data4 = std::thread::spawn(move || thread_closure(slice4));
-    let result = data1.join().unwrap();
-    let result2 = data2.join().unwrap();
-    let result3 = data3.join().unwrap();
-    let result4 = data4.join().unwrap();
-    file_data.extend(result);
-    file_data.extend(result2);
-    file_data.extend(result3);
-    file_data.extend(result4);
-
-    //store data in json for faster loading
-    let data: Vec<u8> = serialize(&file_data).unwrap();
+fn cache_path() -> PathBuf {
     let mut data_path: PathBuf = dirs::config_dir().unwrap();
-    data_path.push("rsmus");
+    data_path.push("rsmus/metadata.bin");
+    return data_path;
+}
 
+// Save the songs for faster loading next time. The cache is written to a
+// temporary file first and renamed over the old one, so a crash part way
+// through never leaves half a cache behind. Failing to write it only costs a
+// scan on the next start.
+fn write_cache(file_data: &Vec<Song>) {
+    let header = CacheHeader {
+        magic: CACHE_MAGIC,
+        version: CACHE_VERSION,
+        library_root: music_dir_path(),
+        written: SystemTime::now(),
+    };
+    let mut data: Vec<u8> = serialize(&header).unwrap();
+    serialize_into(&mut data, file_data).unwrap();
+
+    let data_path = cache_path();
     //create .config dir if it doesnt already exist
-    if !data_path.exists() {
-        fs::create_dir(data_path.clone());
+    if let Some(dir) = data_path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    let temp_path = data_path.with_extension("bin.tmp");
+    let written = File::create(&temp_path).and_then(|mut metadata| {
+        metadata.write_all(&data)?;
+        return metadata.sync_all();
+    });
+    match written {
+        Ok(()) => {
+            let _ = fs::rename(&temp_path, &data_path);
+        }
+        Err(_) => {
+            let _ = fs::remove_file(&temp_path);
+        }
+    }
+}
+
+// Whether `path` has one of the extensions scanned for music.
+fn is_audio_file(path: &Path, extensions: &[String]) -> bool {
+    match path.extension() {
+        Some(extension) => {
+            let extension = extension.to_string_lossy().to_lowercase();
+            return extensions.contains(&extension);
+        }
+        None => return false,
     }
-    data_path.push("metadata.bin");
-    let mut metadata = File::create(data_path).unwrap();
-    metadata.write_all(&data).unwrap();
+}
 
-    //run library_init to init program
-    return file_data;
+// Whether the file at `path` is scanned for music and holds audio.
+fn is_playable(path: &Path, extensions: &[String]) -> bool {
+    return is_audio_file(path, extensions) && Format::of_file(path).is_some();
 }
 
-// Recieves a chunk of files and gets metadata for each valid file type
-fn thread_closure(entries: Vec<DirEntry>) -> Vec<Song> {
-    let mut file_data = Vec::new();
-    for entry in entries {
-        let path = entry.path().to_str().unwrap();
-        if path.ends_with(".flac") {
-            file_data.push(get_file_metadata(entry));
-        } else if path.ends_with(".mp3") {
-            file_data.push(get_file_metadata(entry));
-        } else if path.ends_with(".wav") {
-            file_data.push(get_file_metadata(entry));
+fn enabled_extensions() -> Vec<String> {
+    return config::Config::from_config_file()
+        .extensions
+        .iter()
+        .map(|extension| extension.trim_start_matches('.').to_lowercase())
+        .collect();
+}
+
+// Audio formats the library can hold. They are told apart by what is in the
+// file, as names can't be trusted.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Flac,
+    Mp3,
+    Wav,
+    Vorbis,
+    Opus,
+    // MP4 audio, holding AAC or ALAC.
+    Mp4,
+    Aiff,
+    WavPack,
+}
+
+impl Format {
+    // Read the start of the file at `path` to see what it holds.
+    pub fn of_file(path: &Path) -> Option<Format> {
+        let mut file = File::open(path).ok()?;
+        let mut header = [0; 64];
+        let read = read_fully(&mut file, &mut header);
+        if read < 10 || &header[0..3] != b"ID3" {
+            return Format::sniff(&header[..read]);
         }
+        // Skip the ID3 tag some files start with. Its size is stored seven
+        // bits to a byte and doesn't count the header or footer.
+        let size = header[6..10]
+            .iter()
+            .fold(0, |size, &byte| (size << 7) | (byte & 0x7f) as u64);
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        file.seek(SeekFrom::Start(10 + size + footer)).ok()?;
+        let read = read_fully(&mut file, &mut header);
+        // Anything else with an ID3 tag is almost certainly MP3.
+        return Format::sniff(&header[..read]).or(Some(Format::Mp3));
+    }
+
+    // The format of a file lofty has recognised, if it's one that can be
+    // played.
+    fn of_file_type(file_type: FileType) -> Option<Format> {
+        match file_type {
+            FileType::Flac => return Some(Format::Flac),
+            FileType::Mpeg => return Some(Format::Mp3),
+            FileType::Wav => return Some(Format::Wav),
+            FileType::Vorbis => return Some(Format::Vorbis),
+            FileType::Opus => return Some(Format::Opus),
+            FileType::Mp4 => return Some(Format::Mp4),
+            FileType::Aiff => return Some(Format::Aiff),
+            FileType::WavPack => return Some(Format::WavPack),
+            _ => return None,
+        }
+    }
+
+    fn sniff(header: &[u8]) -> Option<Format> {
+        let at = |start: usize, magic: &[u8]| {
+            header.len() >= start + magic.len()
+                && &header[start..start + magic.len()] == magic
+        };
+        if at(0, b"fLaC") {
+            return Some(Format::Flac);
+        } else if at(0, b"RIFF") && at(8, b"WAVE") {
+            return Some(Format::Wav);
+        } else if at(0, b"FORM") && (at(8, b"AIFF") || at(8, b"AIFC")) {
+            return Some(Format::Aiff);
+        } else if at(4, b"ftyp") {
+            return Some(Format::Mp4);
+        } else if at(0, b"wvpk") {
+            return Some(Format::WavPack);
+        } else if at(0, b"OggS") && header.len() > 27 {
+            // The first packet follows the page's segment table.
+            let packet = 27 + header[26] as usize;
+            if at(packet, b"\x01vorbis") {
+                return Some(Format::Vorbis);
+            } else if at(packet, b"OpusHead") {
+                return Some(Format::Opus);
+            }
+        } else if header.len() >= 2
+            && header[0] == 0xff
+            && header[1] & 0xe0 == 0xe0
+            && header[1] & 0x06 != 0
+        {
+            // An MPEG audio frame. Layer bits of zero would be AAC.
+            return Some(Format::Mp3);
+        }
+        return None;
     }
-    return file_data;
 }
 
-// Gets metadata using taglib, might change in future.
-fn get_file_metadata(entry: DirEntry) -> Song {
-    let file = taglib::File::new(entry.path()).unwrap();
-    let duration = file.audioproperties().unwrap().length();
-    let meta = file.tag().unwrap();
-    return Song {
-        artist: meta.artist().unwrap_or("Unknown".to_string()),
-        album: meta.album().unwrap_or("Unknown".to_string()),
-        title: meta.title().unwrap_or("Unknown".to_string()),
-        path: entry.path().to_str().unwrap().to_string(),
-        duration: Some(Duration::new(duration as u64, 0)),
-        year: meta.year().unwrap_or(0),
-        track: meta.track().unwrap_or(0),
-        genre: meta.genre().unwrap_or("Unknown".to_string()),
+// Fill as much of `buffer` as the file has, returning how much that was.
+fn read_fully(file: &mut File, buffer: &mut [u8]) -> usize {
+    let mut read = 0;
+    while read < buffer.len() {
+        match file.read(&mut buffer[read..]) {
+            Ok(0) | Err(_) => break,
+            Ok(count) => read += count,
+        }
+    }
+    return read;
+}
+
+// Modified time and size, used to tell whether a file changed since it was
+// scanned.
+fn file_stamp(entry: &DirEntry) -> (Option<SystemTime>, u64) {
+    match entry.metadata() {
+        Ok(metadata) => return (metadata.modified().ok(), metadata.len()),
+        Err(_) => return (None, 0),
+    }
+}
+
+// Read a song's tags and length. What format the file is comes from its
+// contents, and the file is only read the once, by lofty.
+fn get_file_metadata(entry: DirEntry) -> Result<Song, ScanFailure> {
+    let failure = |reason: String| ScanFailure::new(entry.path(), reason);
+    let path = match entry.path().to_str() {
+        Some(path) => path.to_string(),
+        None => return Err(failure("name isn't valid UTF-8".to_string())),
     };
+    let probe = match Probe::open(entry.path()) {
+        Ok(probe) => probe,
+        Err(error) => {
+            return Err(failure(format!("can't be opened ({})", error)));
+        }
+    };
+    let probe = match probe.guess_file_type() {
+        Ok(probe) => probe,
+        Err(error) => {
+            return Err(failure(format!("can't be opened ({})", error)));
+        }
+    };
+    if probe.file_type().and_then(Format::of_file_type).is_none() {
+        return Err(failure("not a recognised audio format".to_string()));
+    }
+    let file = match probe.read() {
+        Ok(file) => file,
+        Err(error) => {
+            return Err(failure(format!("can't be read ({})", error)));
+        }
+    };
+    let tags = read_tags(&file);
+    let (modified, size) = file_stamp(&entry);
+    return Ok(Song {
+        id: song_id(&path),
+        artist: tags.artist,
+        album: tags.album,
+        title: tags.title,
+        path: path,
+        duration: Some(file.properties().duration()),
+        year: tags.year,
+        track: tags.track,
+        genre: tags.genre,
+        disc: tags.disc,
+        discs: tags.discs,
+        album_artist: tags.album_artist,
+        compilation: tags.compilation,
+        modified: modified,
+        size: size,
+    });
 }
 
-fn metadata_from_binary(data_path: PathBuf) -> Vec<Song> {
+// The tags a song is made from.
+struct Tags {
+    artist: String,
+    album: String,
+    title: String,
+    genre: String,
+    year: u32,
+    track: u32,
+    album_artist: Option<String>,
+    compilation: bool,
+    disc: u32,
+    discs: u32,
+}
+
+// Each format has its own names for these, ALBUMARTIST / TPE2 / aART,
+// COMPILATION / TCMP / cpil and so on, which lofty maps to the same keys.
+// Files without tags are of an unknown artist and album, and are treated as
+// a single artist's album of one disc.
+fn read_tags(file: &TaggedFile) -> Tags {
+    let mut tags = Tags {
+        artist: "Unknown".to_string(),
+        album: "Unknown".to_string(),
+        title: "Unknown".to_string(),
+        genre: "Unknown".to_string(),
+        year: 0,
+        track: 0,
+        album_artist: None,
+        compilation: false,
+        disc: 0,
+        discs: 0,
+    };
+    let tag = match file.primary_tag().or(file.first_tag()) {
+        Some(tag) => tag,
+        None => return tags,
+    };
+    // Blank tags count as missing.
+    let text = |value: Option<Cow<str>>| {
+        return value
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty());
+    };
+    tags.artist = text(tag.artist()).unwrap_or(tags.artist);
+    tags.album = text(tag.album()).unwrap_or(tags.album);
+    tags.title = text(tag.title()).unwrap_or(tags.title);
+    tags.genre = text(tag.genre()).unwrap_or(tags.genre);
+    tags.year = tag.year().unwrap_or(0);
+    tags.track = tag.track().unwrap_or(0);
+    tags.album_artist = tag
+        .get_string(&ItemKey::AlbumArtist)
+        .map(|artist| artist.trim())
+        .filter(|artist| !artist.is_empty())
+        .map(|artist| artist.to_string());
+    tags.compilation = match tag.get_string(&ItemKey::FlagCompilation) {
+        Some(flag) => flag.trim() == "1" || flag.trim() == "true",
+        None => false,
+    };
+    tags.disc = tag.disk().unwrap_or(0);
+    tags.discs = tag.disk_total().unwrap_or(0);
+    return tags;
+}
+
+fn metadata_from_binary(data_path: PathBuf) -> Option<Vec<Song>> {
     // Open data file and read binary to objects.
-    let mut data_file = File::open(data_path).unwrap();
-    let mut buffer = Vec::new();
-    data_file.read_to_end(&mut buffer).unwrap();
+    let buffer = fs::read(data_path).ok()?;
+    let mut data = &buffer[..];
+    let header: CacheHeader = match deserialize_from(&mut data) {
+        Ok(header) => header,
+        Err(_) => return None,
+    };
+    if header.magic != CACHE_MAGIC {
+        return None;
+    }
+    if header.library_root != music_dir_path() {
+        return None;
+    }
+
+    // Create song objects with data. A truncated or otherwise corrupt
+    // cache fails here rather than giving half a library.
+    match header.version {
+        CACHE_VERSION => return deserialize_from(&mut data).ok(),
+        3 => return migrate_v3(&mut data),
+        _ => return None,
+    }
+}
+
+// Song as cached by version 3, before songs had IDs.
+#[derive(Deserialize)]
+struct SongV3 {
+    artist: String,
+    album: String,
+    title: String,
+    path: String,
+    duration: Option<std::time::Duration>,
+    track: u32,
+    year: u32,
+    genre: String,
+    disc: u32,
+    discs: u32,
+    album_artist: Option<String>,
+    compilation: bool,
+    modified: Option<SystemTime>,
+    size: u64,
+}
+
+// IDs come from the paths, so nothing has to be scanned again.
+fn migrate_v3(data: &mut &[u8]) -> Option<Vec<Song>> {
+    let old_songs: Vec<SongV3> = deserialize_from(data).ok()?;
+    let songs: Vec<Song> = old_songs
+        .into_iter()
+        .map(|song| Song {
+            id: song_id(&song.path),
+            artist: song.artist,
+            album: song.album,
+            title: song.title,
+            path: song.path,
+            duration: song.duration,
+            track: song.track,
+            year: song.year,
+            genre: song.genre,
+            disc: song.disc,
+            discs: song.discs,
+            album_artist: song.album_artist,
+            compilation: song.compilation,
+            modified: song.modified,
+            size: song.size,
+        })
+        .collect();
+    write_cache(&songs);
+    return Some(songs);
+}
 
-    // Create song objects with data.
-    let songs: Vec<Song> = deserialize(&buffer[..]).unwrap();
-    return songs;
+// Hands out one shared copy of each distinct string, so a name that is on
+// thousands of songs is only stored once.
+struct Interner<'a> {
+    strings: HashMap<&'a str, Arc<str>>,
 }
 
-pub fn init_albums(file_data: &Vec<Song>) -> Vec<Album> {
-    // Get list of alphabetically sorted unique albums in library.
-    let mut albums_list = Vec::new();
-    for song in file_data.iter() {
-        albums_list.push(&song.album);
+impl<'a> Interner<'a> {
+    fn new() -> Interner<'a> {
+        return Interner {
+            strings: HashMap::new(),
+        };
     }
-    albums_list.sort();
-    albums_list.dedup();
 
-    // Create list of album objects containing songs and info.
-    let mut albums = Vec::new();
+    fn intern(&mut self, string: &'a str) -> Arc<str> {
+        return self
+            .strings
+            .entry(string)
+            .or_insert_with(|| Arc::from(string))
+            .clone();
+    }
+}
 
-    // TODO: Find a way to differentiate albums with same name while
-    // preserving the display of split LPs in both artists' view.
-    // Probably by album release date.
-    for album in albums_list {
-        let songs: Vec<&Song> = file_data
+// Group songs into albums. Compilations are credited to `various_artists`
+// rather than every artist on them. Each song is looked at a fixed number
+// of times, so this stays quick for libraries of hundreds of thousands.
+pub fn init_albums<'a, I>(file_data: I, various_artists: &'a str) -> Vec<Album>
+where
+    I: Iterator<Item = &'a Song>,
+{
+    // Songs of one release share a title, a year and usually a directory.
+    // Grouping by directory first keeps apart albums that only share a
+    // title, like every "Greatest Hits", while the artists of a split LP
+    // still end up on the same album.
+    let mut releases: HashMap<(&str, u32, &Path), Vec<&Song>> = HashMap::new();
+    for song in file_data {
+        let directory = Path::new(&song.path).parent().unwrap_or(Path::new(""));
+        releases
+            .entry((&song.album, song.year, directory))
+            .or_insert(Vec::new())
+            .push(song);
+    }
+
+    // A release spread over several directories, one per disc say, has
+    // the same album artists in each, so those are put back together.
+    let mut grouped: HashMap<(Vec<&str>, &str, u32), Vec<&Song>> =
+        HashMap::new();
+    for ((title, year, _), songs) in releases {
+        let mut artists: Vec<&str> = songs
             .iter()
-            .filter(|song| &song.album == album)
+            .map(|song| song.album_artist().unwrap_or(various_artists))
             .collect();
-        let mut artists: Vec<&str> =
-            songs.iter().map(|song| song.artist.as_ref()).collect();
         artists.sort();
         artists.dedup();
+        grouped
+            .entry((artists, title, year))
+            .or_insert(Vec::new())
+            .extend(songs);
+    }
+
+    // Create list of album objects containing songs and info.
+    let mut names = Interner::new();
+    let mut albums = Vec::with_capacity(grouped.len());
+    for ((artists, title, year), songs) in grouped {
+        let mut songs = songs;
+        songs.sort_by_cached_key(|song| track_order(song));
+        let mut track_artists: Vec<&str> = songs
+            .iter()
+            .filter(|song| song.compilation)
+            .map(|song| song.artist.as_ref())
+            .collect();
+        track_artists.sort();
+        track_artists.dedup();
         albums.push(Album {
-            artists: artists,
-            songs: songs,
-            title: album,
+            songs: songs.iter().map(|song| song.id).collect(),
+            discs: songs.iter().map(|song| song.disc).collect(),
+            disc_total: songs.iter().map(|song| song.discs).max().unwrap_or(0),
+            artists: artists
+                .into_iter()
+                .map(|artist| names.intern(artist))
+                .collect(),
+            track_artists: track_artists
+                .into_iter()
+                .map(|artist| names.intern(artist))
+                .collect(),
+            title: names.intern(title),
+            year: year,
         });
     }
+    // Alphabetically, with albums of the same title oldest first.
+    albums.sort_by(|a, b| {
+        (&a.title, a.year, &a.artists).cmp(&(&b.title, b.year, &b.artists))
+    });
     return albums;
 }
 
-pub fn init_artists<'a>(
-    songs: &'a Vec<Song>,
-    albums: &'a Vec<Album>,
-) -> Vec<Artist<'a>> {
-    //create alphabetically sorted list of unique artists
-    let mut artists_list = Vec::new();
-    for song in songs.iter() {
-        artists_list.push(&song.artist)
-    }
-    artists_list.sort();
-    artists_list.dedup();
+// Where a song comes on its album. Untagged songs are ordered by file name,
+// which is usually numbered, and then by directory, which tells the discs
+// of a set ripped into one directory each apart.
+fn track_order(song: &Song) -> (u32, u32, Option<&OsStr>, &str) {
+    let file_name = Path::new(&song.path).file_name();
+    return (song.disc, song.track, file_name, &song.path);
+}
 
-    //create a list of artist objects containing albums and info
-    let mut artists = Vec::new();
+pub fn init_artists(albums: &Vec<Album>) -> Vec<Artist> {
+    // Every album goes under its album artists and, for a compilation, the
+    // artists of its songs. Someone who is only on compilations still gets
+    // an entry to find them through.
+    let mut by_artist: HashMap<Arc<str>, Vec<usize>> = HashMap::new();
+    for (index, album) in albums.iter().enumerate() {
+        for name in album.artists.iter().chain(album.track_artists.iter()) {
+            let listed = by_artist.entry(name.clone()).or_insert(Vec::new());
+            // Albums are gone through in order, so a repeat is the last one.
+            if listed.last() != Some(&index) {
+                listed.push(index);
+            }
+        }
+    }
 
-    for artist in artists_list {
-        let albums: Vec<&Album> = albums
-            .iter()
-            .filter(|album| {
-                album.artists.binary_search(&&artist.as_ref()).is_ok()
-            })
-            .collect();
-        artists.push(Artist {
+    // Alphabetically sorted list of artist objects.
+    let mut artists: Vec<Artist> = by_artist
+        .into_iter()
+        .map(|(name, albums)| Artist {
             albums: albums,
-            name: artist,
-        });
-    }
+            name: name,
+        })
+        .collect();
+    artists.sort_by(|a, b| a.name.cmp(&b.name));
     return artists;
 }
+// Identifies a song by its path. The same file gets the same ID every run,
+// so IDs can be saved along with songs.
+pub type SongId = u64;
+
+// 64-bit FNV-1a of the path. Std's hasher may change between releases.
+pub fn song_id(path: &str) -> SongId {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in path.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Song {
+    pub id: SongId,
     pub artist: String,
     pub album: String,
     pub title: String,
@@ -196,16 +1001,151 @@ pub struct Song {
     pub track: u32,
     pub year: u32,
     pub genre: String,
+    // Disc of a multi-disc set the song is on and how many there are, zero
+    // when not tagged.
+    pub disc: u32,
+    pub discs: u32,
+    // ALBUMARTIST, if the song has it.
+    pub album_artist: Option<String>,
+    // Part of an album of different artists' songs.
+    pub compilation: bool,
+    // When the file was last modified and its size, as of the last scan.
+    pub modified: Option<SystemTime>,
+    pub size: u64,
+}
+
+// A song at `path` with the given names and made up everything else, for
+// tests to change what they need.
+#[cfg(test)]
+pub fn test_song(artist: &str, album: &str, title: &str, path: &str) -> Song {
+    return Song {
+        id: song_id(path),
+        artist: artist.to_string(),
+        album: album.to_string(),
+        title: title.to_string(),
+        path: path.to_string(),
+        duration: Some(std::time::Duration::from_secs(200)),
+        track: 1,
+        year: 2000,
+        genre: "Electronic".to_string(),
+        disc: 1,
+        discs: 1,
+        album_artist: None,
+        compilation: false,
+        modified: None,
+        size: 0,
+    };
+}
+
+// The album artist, title and year of a song's album. Enough to tell
+// whether two songs are from the same album when only the songs are at
+// hand. Compilations have no album artist.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct AlbumKey {
+    pub artist: Option<String>,
+    pub title: String,
+    pub year: u32,
+}
+
+impl Song {
+    // Who the song's album is by, falling back on the song's own artist.
+    // None for compilations, which are by various artists.
+    pub fn album_artist(&self) -> Option<&str> {
+        if self.compilation {
+            return None;
+        }
+        match self.album_artist {
+            Some(ref artist) => return Some(artist),
+            None => return Some(&self.artist),
+        }
+    }
+
+    pub fn album_key(&self) -> AlbumKey {
+        return AlbumKey {
+            artist: self.album_artist().map(|artist| artist.to_string()),
+            title: self.album.clone(),
+            year: self.year,
+        };
+    }
 }
 
 #[derive(Clone)]
-pub struct Album<'a> {
-    pub songs: Vec<&'a Song>,
-    pub artists: Vec<&'a str>,
-    pub title: &'a str,
+pub struct Album {
+    // In track order.
+    pub songs: Vec<SongId>,
+    // Disc each of `songs` is on, and the most discs any of them says the
+    // set has.
+    pub discs: Vec<u32>,
+    pub disc_total: u32,
+    // Album artists, or the various artists entry for a compilation.
+    pub artists: Vec<Arc<str>>,
+    // Artists of the songs on a compilation, who it is also listed under.
+    pub track_artists: Vec<Arc<str>>,
+    pub title: Arc<str>,
+    pub year: u32,
+}
+
+impl Album {
+    // Whether the songs are spread over more than one disc.
+    pub fn is_multi_disc(&self) -> bool {
+        return self.disc_total > 1
+            || self.discs.iter().any(|&disc| disc != self.discs[0]);
+    }
+
+    // Number of different discs the songs are on.
+    pub fn disc_count(&self) -> usize {
+        let mut discs = self.discs.clone();
+        discs.dedup();
+        return discs.len();
+    }
+
+    // Whether the album belongs under `artist` in the artist pane.
+    pub fn is_by(&self, artist: &str) -> bool {
+        let found = |names: &Vec<Arc<str>>| {
+            names.binary_search_by(|name| (**name).cmp(artist)).is_ok()
+        };
+        return found(&self.artists) || found(&self.track_artists);
+    }
 }
 #[derive(Clone)]
-pub struct Artist<'a> {
-    pub albums: Vec<&'a Album<'a>>,
-    pub name: &'a str,
+pub struct Artist {
+    // Indices into the albums the artist was built from.
+    pub albums: Vec<usize>,
+    pub name: Arc<str>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_renamed_file() {
+        let moved = moved_path(
+            "/m/a.mp3",
+            Path::new("/m/a.mp3"),
+            Path::new("/m/b.mp3"),
+        );
+        assert_eq!(moved, Some("/m/b.mp3".to_string()));
+    }
+
+    #[test]
+    fn moves_songs_in_renamed_dir() {
+        let from = Path::new("/m/old");
+        let to = Path::new("/m/new");
+        let moved = moved_path("/m/old/cd1/01.flac", from, to);
+        assert_eq!(moved, Some("/m/new/cd1/01.flac".to_string()));
+        assert_eq!(moved_path("/m/older/01.flac", from, to), None);
+        assert_eq!(moved_path("/m/other/01.flac", from, to), None);
+    }
+
+    #[test]
+    fn reads_nested_updates_once() {
+        let paths = ["/m/b/01.flac", "/m/a-c", "/m/b", "/m/a/cd1", "/m/b"];
+        let roots = outermost(paths.iter().map(PathBuf::from).collect());
+        let expected = ["/m/a/cd1", "/m/a-c", "/m/b"];
+        assert_eq!(
+            roots,
+            expected.iter().map(PathBuf::from).collect::<Vec<_>>()
+        );
+    }
 }
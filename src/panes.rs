@@ -1,7 +1,15 @@
-use crate::metadata::{Album, Artist, Song};
+use crate::library::Library;
+use crate::metadata::{Album, Artist, ScanProgress, ScanReport, Song, SongId};
+use crate::player::Player;
+use crate::query::QueryError;
+use crate::queue::{Queue, Repeat, Shuffle};
+use crate::search::{self, SearchResult};
 use crate::FocusedPane;
 use std::boxed::Box;
+use std::collections::BTreeMap;
 use std::io::{Stdout, Write};
+use std::sync::Arc;
+use std::time::Duration;
 use termion::color;
 use termion::cursor;
 use termion::raw::RawTerminal;
@@ -21,8 +29,24 @@ enum PaneType {
     AlbumPane,
 }
 
-pub struct Pane<'a> {
-    options: Vec<&'a str>,
+// What an option in a pane is, which unlike its index stays the same when
+// songs are added or removed elsewhere in the library.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+enum Item {
+    Artist(String),
+    Album(String, u32),
+    Song(SongId),
+}
+
+// What a pane had highlighted and how far down the pane it was.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Cursor {
+    cursor_pos: usize,
+    item: Item,
+}
+
+pub struct Pane {
+    options: Vec<String>,
     reference: usize,
     height: u16,
     width: u16,
@@ -30,19 +54,32 @@ pub struct Pane<'a> {
     pos: (u16, u16),
     focus: FocusedPane,
     pane_type: PaneType,
-    pub child_pane: Option<Box<Pane<'a>>>,
-    album: Option<&'a Album<'a>>,
+    pub child_pane: Option<Box<Pane>>,
+    album: Option<Album>,
+    // What each of `options` is, as an index into the artists for the
+    // artist pane, into the albums for an artist's albums and into the
+    // album's songs for the album view. Names can be shared, these can't.
+    items: Vec<usize>,
+    // What each of `options` is, for finding it again once the library
+    // has changed and `items` no longer line up.
+    keys: Vec<Item>,
 }
-impl<'a> Pane<'a> {
-    pub fn init_artist_pane(
-        artists: &'a Vec<Artist>,
-        albums: &'a Vec<Album>,
-        size: (u16, u16),
-    ) -> Pane<'a> {
-        let options: Vec<&str> =
-            artists.iter().map(|artist| artist.name).collect();
+impl Pane {
+    pub fn init_artist_pane(library: &Library, size: (u16, u16)) -> Pane {
+        let artists = library.artists();
+        let options: Vec<String> = artists
+            .iter()
+            .map(|artist| artist.name.to_string())
+            .collect();
         let height = size.1;
         let width = size.0 / 5;
+        // The library is empty until the first scan is done.
+        let child_pane = match artists.first() {
+            Some(artist) => Some(Box::new(Pane::init_artist_album_pane(
+                artist, library, size,
+            ))),
+            None => None,
+        };
         return Pane {
             reference: 0 as usize,
             height: height,
@@ -50,32 +87,34 @@ impl<'a> Pane<'a> {
             cursor_pos: 0,
             pos: (1, 2),
             album: None,
+            items: (0..artists.len()).collect(),
+            keys: artists
+                .iter()
+                .map(|artist| Item::Artist(artist.name.to_string()))
+                .collect(),
             focus: FocusedPane::Pane1,
             pane_type: PaneType::MenuPane,
-            child_pane: Some(Box::new(Pane::init_artist_album_pane(
-                &options[0],
-                albums,
-                size,
-            ))),
+            child_pane: child_pane,
             options: options,
         };
     }
 
     fn init_artist_album_pane(
-        artist: &str,
-        albums: &'a Vec<Album>,
+        artist: &Artist,
+        library: &Library,
         size: (u16, u16),
-    ) -> Pane<'a> {
-        let options: Vec<&str> = albums
+    ) -> Pane {
+        let albums = library.albums();
+        let options: Vec<String> = artist
+            .albums
             .iter()
-            .filter(|album| album.artists.binary_search(&artist).is_ok())
-            .map(|album| album.title)
+            .map(|&index| albums[index].title.to_string())
             .collect();
         let height = size.1;
         let width = size.0 / 5;
         let x = size.0 / 5 + 2;
         return Pane {
-            options: options.clone(),
+            options: options,
             reference: 0 as usize,
             height: height,
             width: width,
@@ -85,24 +124,32 @@ impl<'a> Pane<'a> {
             pane_type: PaneType::MenuPane,
             focus: FocusedPane::Pane2,
             child_pane: Some(Box::new(Pane::init_album_view_pane(
-                &options[0],
-                albums,
+                &albums[artist.albums[0]],
+                library,
                 size,
             ))),
+            items: artist.albums.clone(),
+            keys: artist
+                .albums
+                .iter()
+                .map(|&index| {
+                    let album = &albums[index];
+                    Item::Album(album.title.to_string(), album.year)
+                })
+                .collect(),
         };
     }
 
     fn init_album_view_pane(
-        album_title: &str,
-        albums: &'a Vec<Album>,
+        album: &Album,
+        library: &Library,
         size: (u16, u16),
-    ) -> Pane<'a> {
-        let album: &Album = albums
-            .into_iter()
-            .find(|album| album.title == album_title)
-            .unwrap();
-        let options: Vec<&str> =
-            album.songs.iter().map(|song| song.title.as_ref()).collect();
+    ) -> Pane {
+        let options: Vec<String> = library
+            .album_songs(album)
+            .iter()
+            .map(|song| song.title.clone())
+            .collect();
         let height = size.1;
         let width = size.0 - (size.0 / 5 * 2) - 1;
         let x = (size.0 / 5) * 2 + 2;
@@ -113,7 +160,9 @@ impl<'a> Pane<'a> {
             width: width,
             cursor_pos: 0,
             pos: (x, 2),
-            album: Some(album),
+            album: Some(album.clone()),
+            items: (0..album.songs.len()).collect(),
+            keys: album.songs.iter().map(|&id| Item::Song(id)).collect(),
             pane_type: PaneType::AlbumPane,
             focus: FocusedPane::Pane3,
             child_pane: None,
@@ -152,16 +201,21 @@ impl<'a> Pane<'a> {
         let x = self.pos.0;
         let mut y = self.pos.1;
         draw_box(stdout, self.width, self.height, (x, y - 1));
-        let mut shown_options: &[&str] = &[];
-        if self.options.len() > self.height as usize {
-            shown_options = &self.options
-                [self.reference..(self.height as usize + self.reference)];
+        let rows = self.rows() as usize;
+        let mut shown_options: &[String] = &[];
+        if self.options.len() > rows {
+            shown_options =
+                &self.options[self.reference..(rows + self.reference)];
         } else {
             shown_options = &self.options[..]
         }
 
         let mut title: &str = "";
-        let mut artists: &Vec<&str> = &vec![];
+        let mut artists: &Vec<Arc<str>> = &vec![];
+        let multi_disc = match self.album {
+            Some(ref album) => album.is_multi_disc(),
+            None => false,
+        };
 
         write!(stdout, "{}", cursor::Goto(x, y));
         match &self.album {
@@ -187,6 +241,25 @@ impl<'a> Pane<'a> {
 
         y += 1;
         for num in 0..shown_options.len() {
+            // A heading above the first song of each disc, and above the
+            // top one so a scrolled list still says which disc it is on.
+            if let (true, Some(ref album)) = (multi_disc, &self.album) {
+                let index = self.reference + num;
+                let disc = album.discs[index];
+                let starts_disc = num == 0 || album.discs[index - 1] != disc;
+                if starts_disc && disc > 0 {
+                    write!(
+                        stdout,
+                        "{}{}{}Disc {}{}",
+                        cursor::Goto(x, y),
+                        VERT_BOUNDARY,
+                        Bold,
+                        disc,
+                        NoBold
+                    );
+                    y += 1;
+                }
+            }
             let mut option = shown_options[num as usize].to_string();
 
             if option.chars().count() > self.width as usize - 4 {
@@ -213,7 +286,7 @@ impl<'a> Pane<'a> {
         stdout: &mut RawTerminal<Stdout>,
         focused_pane: &FocusedPane,
     ) {
-        let mut shown_options: &[&str] = &[];
+        let mut shown_options: &[String] = &[];
         if self.options.len() > self.height as usize {
             shown_options = &self.options
                 [self.reference..(self.height as usize + self.reference)];
@@ -262,23 +335,28 @@ impl<'a> Pane<'a> {
         }
     }
 
-    pub fn move_down(&mut self, albums: &'a Vec<Album>, size: (u16, u16)) {
-        if (self.reference as i16)
-            < (self.options.len() as i16 - self.height as i16)
-        {
-            if self.cursor_pos < self.height as usize - 1 {
+    pub fn move_down(&mut self, library: &Library, size: (u16, u16)) {
+        if self.options.is_empty() {
+            return;
+        }
+        let rows = self.rows();
+        if (self.reference as i16) < (self.options.len() as i16 - rows as i16) {
+            if self.cursor_pos < rows as usize - 1 {
                 self.cursor_pos += 1;
             } else {
                 self.reference += 1;
             }
-        } else if self.cursor_pos < self.height as usize - 1
+        } else if self.cursor_pos < rows as usize - 1
             && self.cursor_pos < (self.options.len() - 1)
         {
             self.cursor_pos += 1;
         }
-        self.reset_child(albums, size);
+        self.reset_child(library, size);
     }
-    pub fn move_up(&mut self, albums: &'a Vec<Album>, size: (u16, u16)) {
+    pub fn move_up(&mut self, library: &Library, size: (u16, u16)) {
+        if self.options.is_empty() {
+            return;
+        }
         if self.reference > 0 {
             if self.cursor_pos > 0 {
                 self.cursor_pos -= 1;
@@ -288,23 +366,19 @@ impl<'a> Pane<'a> {
         } else if self.cursor_pos > 0 {
             self.cursor_pos -= 1;
         }
-        self.reset_child(albums, size);
+        self.reset_child(library, size);
     }
 
-    pub fn move_child_down(
-        &mut self,
-        albums: &'a Vec<Album>,
-        size: (u16, u16),
-    ) {
+    pub fn move_child_down(&mut self, library: &Library, size: (u16, u16)) {
         match self.child_pane {
-            Some(ref mut pane) => pane.move_down(albums, size),
+            Some(ref mut pane) => pane.move_down(library, size),
             None => {}
         }
     }
 
-    pub fn move_child_up(&mut self, albums: &'a Vec<Album>, size: (u16, u16)) {
+    pub fn move_child_up(&mut self, library: &Library, size: (u16, u16)) {
         match self.child_pane {
-            Some(ref mut pane) => pane.move_up(albums, size),
+            Some(ref mut pane) => pane.move_up(library, size),
             None => {}
         }
     }
@@ -320,35 +394,529 @@ impl<'a> Pane<'a> {
         return &self.options[self.reference + self.cursor_pos];
     }
 
-    pub fn reset_child<'b>(
+    pub fn get_child_selected_songs(&self, library: &Library) -> Vec<Song> {
+        match self.child_pane {
+            Some(ref pane) => return pane.get_selected_songs(library),
+            None => return Vec::new(),
+        }
+    }
+
+    // Songs of the album shown in this pane, from the highlighted one to the
+    // end of the album.
+    pub fn get_selected_songs(&self, library: &Library) -> Vec<Song> {
+        match self.album {
+            Some(ref album) => {
+                let first = self.items[self.reference + self.cursor_pos];
+                return album.songs[first..]
+                    .iter()
+                    .filter_map(|&id| library.song(id))
+                    .cloned()
+                    .collect();
+            }
+            None => return Vec::new(),
+        }
+    }
+
+    // Highlighted options of this pane and the ones below it.
+    pub fn get_cursors(&self) -> Vec<Cursor> {
+        let index = self.reference + self.cursor_pos;
+        let mut cursors = match self.keys.get(index) {
+            Some(item) => vec![Cursor {
+                cursor_pos: self.cursor_pos,
+                item: item.clone(),
+            }],
+            None => return Vec::new(),
+        };
+        if let Some(ref pane) = self.child_pane {
+            cursors.extend(pane.get_cursors());
+        }
+        return cursors;
+    }
+
+    // Put back positions from `get_cursors`, finding each highlighted option
+    // by what it is, as the library may have changed since. Stops at the
+    // first one that is gone.
+    pub fn set_cursors(
+        &mut self,
+        cursors: &[Cursor],
+        library: &Library,
+        size: (u16, u16),
+    ) {
+        let cursor = match cursors.first() {
+            Some(cursor) => cursor,
+            None => return,
+        };
+        let index = match self.keys.iter().position(|key| key == &cursor.item) {
+            Some(index) => index,
+            None => return,
+        };
+        self.show(index, cursor.cursor_pos);
+        self.reset_child(library, size);
+        if let Some(ref mut pane) = self.child_pane {
+            pane.set_cursors(&cursors[1..], library, size);
+        }
+    }
+
+    // Highlight the options at these indices in this pane and the ones
+    // below it.
+    pub fn select(
         &mut self,
-        albums: &'a Vec<Album>,
+        location: &[usize],
+        library: &Library,
         size: (u16, u16),
     ) {
+        let index = match location.first() {
+            Some(&index) if index < self.options.len() => index,
+            _ => return,
+        };
+        self.show(index, index);
+        self.reset_child(library, size);
+        if let Some(ref mut pane) = self.child_pane {
+            pane.select(&location[1..], library, size);
+        }
+    }
+
+    // Highlight the option at `index`, `cursor_pos` rows down if that
+    // doesn't scroll past either end of the options. The terminal may also
+    // be smaller than when `cursor_pos` was saved.
+    fn show(&mut self, index: usize, cursor_pos: usize) {
+        let rows = self.rows() as usize;
+        let last_reference = self.options.len().saturating_sub(rows);
+        self.cursor_pos = cursor_pos.min(rows.saturating_sub(1)).min(index);
+        self.reference = (index - self.cursor_pos).min(last_reference);
+        self.cursor_pos = index - self.reference;
+    }
+
+    // Rows there are for options. The album view gives some up to the
+    // "Disc N" headings of a multi-disc album, its box is still full height.
+    fn rows(&self) -> u16 {
+        match self.album {
+            Some(ref album) if album.is_multi_disc() => {
+                let headings = album.disc_count() as u16;
+                return self.height.saturating_sub(headings).max(1);
+            }
+            _ => return self.height,
+        }
+    }
+
+    pub fn reset_child<'b>(&mut self, library: &Library, size: (u16, u16)) {
+        // The library may have changed since this pane was built, in which
+        // case it is about to be built again.
+        let selected = self.items[self.reference + self.cursor_pos];
         match self.child_pane {
             Some(ref p) => match p.pane_type {
-                PaneType::MenuPane => {
-                    self.child_pane =
-                        Some(Box::new(Pane::init_artist_album_pane(
-                            self.get_selected(),
-                            albums,
-                            size,
-                        )));
-                }
-                PaneType::AlbumPane => {
-                    self.child_pane =
-                        Some(Box::new(Pane::init_album_view_pane(
-                            self.get_selected(),
-                            albums,
-                            size,
-                        )));
-                }
+                PaneType::MenuPane => match library.artists().get(selected) {
+                    Some(artist) => {
+                        self.child_pane = Some(Box::new(
+                            Pane::init_artist_album_pane(artist, library, size),
+                        ));
+                    }
+                    None => {}
+                },
+                PaneType::AlbumPane => match library.albums().get(selected) {
+                    Some(album) => {
+                        self.child_pane = Some(Box::new(
+                            Pane::init_album_view_pane(album, library, size),
+                        ));
+                    }
+                    None => {}
+                },
             },
             None => {}
         }
     }
 }
 
+// Full screen list of the play queue, shown instead of the library panes.
+// It draws straight from the queue, so it is never out of date.
+pub struct QueuePane {
+    reference: usize,
+    cursor_pos: usize,
+}
+
+impl QueuePane {
+    pub fn new() -> QueuePane {
+        return QueuePane {
+            reference: 0,
+            cursor_pos: 0,
+        };
+    }
+
+    // Queue index of the highlighted song.
+    pub fn get_selected(&self) -> usize {
+        return self.reference + self.cursor_pos;
+    }
+
+    // Highlight the song at `index`, scrolling to it if needed.
+    pub fn select(&mut self, index: usize, size: (u16, u16)) {
+        let height = size.1 as usize;
+        if index < self.reference {
+            self.reference = index;
+        } else if index >= self.reference + height {
+            self.reference = index + 1 - height;
+        }
+        self.cursor_pos = index - self.reference;
+    }
+
+    pub fn move_down(&mut self, queue: &Queue, size: (u16, u16)) {
+        if self.get_selected() + 1 < queue.len() {
+            self.select(self.get_selected() + 1, size);
+        }
+    }
+
+    pub fn move_up(&mut self, size: (u16, u16)) {
+        if self.get_selected() > 0 {
+            self.select(self.get_selected() - 1, size);
+        }
+    }
+
+    pub fn draw(
+        &mut self,
+        stdout: &mut RawTerminal<Stdout>,
+        queue: &Queue,
+        size: (u16, u16),
+    ) {
+        // The queue may have shrunk since the last draw.
+        if self.get_selected() >= queue.len() {
+            self.reference = 0;
+            self.cursor_pos = 0;
+            if !queue.is_empty() {
+                self.select(queue.len() - 1, size);
+            }
+        }
+        let width = size.0 as usize;
+        draw_box(stdout, size.0, size.1, (1, 1));
+        let shown = queue.songs().iter().enumerate().skip(self.reference);
+        for (row, (index, song)) in shown.take(size.1 as usize).enumerate() {
+            let duration = match song.duration {
+                Some(duration) => format_duration(duration),
+                None => "-:--".to_string(),
+            };
+            let mut option = format!(
+                "{:>4}. {} \u{2013} {}",
+                index + 1,
+                song.artist,
+                song.title
+            );
+            let space = width.saturating_sub(duration.chars().count() + 1);
+            if option.chars().count() > space {
+                option = option.chars().take(space.saturating_sub(2)).collect();
+                option.push_str("..");
+            }
+            while option.chars().count() < space {
+                option.push(' ');
+            }
+            write!(stdout, "{}", cursor::Goto(2, row as u16 + 2)).unwrap();
+            if queue.position() == Some(index) {
+                write!(stdout, "{}", Bold).unwrap();
+            }
+            if row == self.cursor_pos {
+                write!(stdout, "{}", Invert).unwrap();
+            }
+            write!(stdout, "{} {}{}{}", option, duration, NoInvert, Reset)
+                .unwrap();
+        }
+    }
+}
+
+// Full screen list of the files the last scan couldn't read.
+pub struct ReportPane {
+    reference: usize,
+}
+
+impl ReportPane {
+    pub fn new() -> ReportPane {
+        return ReportPane { reference: 0 };
+    }
+
+    pub fn scroll_down(&mut self, report: &ScanReport, size: (u16, u16)) {
+        // The first row holds the heading.
+        let rows = size.1 as usize - 1;
+        if self.reference + rows < report.failures.len() {
+            self.reference += 1;
+        }
+    }
+
+    pub fn scroll_up(&mut self) {
+        if self.reference > 0 {
+            self.reference -= 1;
+        }
+    }
+
+    pub fn draw(
+        &mut self,
+        stdout: &mut RawTerminal<Stdout>,
+        report: &ScanReport,
+        size: (u16, u16),
+    ) {
+        // The report may have changed since the last draw.
+        if self.reference >= report.failures.len() {
+            self.reference = 0;
+        }
+        let width = size.0 as usize;
+        draw_box(stdout, size.0, size.1, (1, 1));
+        let heading = match report.failures.len() {
+            0 => "The last scan read every file.".to_string(),
+            1 => "1 file or directory couldn't be read:".to_string(),
+            count => {
+                format!("{} files or directories couldn't be read:", count)
+            }
+        };
+        write!(stdout, "{}{}{}{}", cursor::Goto(2, 2), Bold, heading, Reset)
+            .unwrap();
+        let shown = report.failures.iter().skip(self.reference);
+        for (row, failure) in shown.take(size.1 as usize - 1).enumerate() {
+            let mut line = format!("{}: {}", failure.path, failure.reason);
+            if line.chars().count() > width {
+                line = line.chars().take(width.saturating_sub(2)).collect();
+                line.push_str("..");
+            }
+            write!(stdout, "{}{}", cursor::Goto(2, row as u16 + 3), line)
+                .unwrap();
+        }
+    }
+}
+
+// Full screen search of the library, with a prompt on top and the results
+// below it, grouped by kind. Results are looked up again whenever the
+// query or the library changes.
+pub struct SearchPane {
+    query: String,
+    results: Vec<SearchResult>,
+    // Why the query couldn't be read, if it couldn't.
+    error: Option<QueryError>,
+    // Smart playlists from the config, listed before anything is typed.
+    playlists: BTreeMap<String, String>,
+    // Index of the highlighted result.
+    selected: usize,
+    // First row of results shown.
+    reference: usize,
+}
+
+// A line of the results: a heading or one of the results.
+enum SearchRow {
+    Heading(&'static str),
+    Result(usize),
+}
+
+impl SearchPane {
+    pub fn new(playlists: BTreeMap<String, String>) -> SearchPane {
+        return SearchPane {
+            query: String::new(),
+            results: Vec::new(),
+            error: None,
+            playlists: playlists,
+            selected: 0,
+            reference: 0,
+        };
+    }
+
+    // Start a new search.
+    pub fn clear(&mut self, library: &Library) {
+        self.query.clear();
+        self.search(library);
+    }
+
+    pub fn push(&mut self, c: char, library: &Library) {
+        self.query.push(c);
+        self.search(library);
+    }
+
+    pub fn pop(&mut self, library: &Library) {
+        self.query.pop();
+        self.search(library);
+    }
+
+    // Search again after the library changed. The highlighted result stays
+    // highlighted if it is still found, else the highlight stays where it
+    // was.
+    pub fn refresh(&mut self, library: &Library) {
+        let selected = self.get_selected();
+        self.run(library);
+        let found = self
+            .results
+            .iter()
+            .position(|result| Some(result) == selected.as_ref());
+        self.selected = match found {
+            Some(index) => index,
+            None => self.selected.min(self.results.len().saturating_sub(1)),
+        };
+    }
+
+    // Search for what has been typed, best result first.
+    fn search(&mut self, library: &Library) {
+        self.run(library);
+        self.selected = 0;
+        self.reference = 0;
+    }
+
+    fn run(&mut self, library: &Library) {
+        match search::search(library, &self.query, &self.playlists) {
+            Ok(results) => {
+                self.results = results;
+                self.error = None;
+            }
+            Err(error) => {
+                self.results.clear();
+                self.error = Some(error);
+            }
+        }
+    }
+
+    pub fn get_selected(&self) -> Option<SearchResult> {
+        return self.results.get(self.selected).cloned();
+    }
+
+    pub fn move_down(&mut self) {
+        if self.selected + 1 < self.results.len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn move_up(&mut self) {
+        if self.selected > 0 {
+            self.selected -= 1;
+        }
+    }
+
+    // The results with a heading above each kind.
+    fn rows(&self) -> Vec<SearchRow> {
+        let mut rows = Vec::new();
+        let mut previous = None;
+        for (index, result) in self.results.iter().enumerate() {
+            let heading = match result {
+                SearchResult::Artist(_) => "Artists",
+                SearchResult::Album(_) => "Albums",
+                SearchResult::Song(_) => "Tracks",
+                SearchResult::Songs(..) if self.query.trim().is_empty() => {
+                    "Smart playlists"
+                }
+                SearchResult::Songs(..) => "Query",
+            };
+            if previous != Some(heading) {
+                rows.push(SearchRow::Heading(heading));
+                previous = Some(heading);
+            }
+            rows.push(SearchRow::Result(index));
+        }
+        return rows;
+    }
+
+    pub fn draw(
+        &mut self,
+        stdout: &mut RawTerminal<Stdout>,
+        library: &Library,
+        size: (u16, u16),
+    ) {
+        let width = size.0 as usize;
+        draw_box(stdout, size.0, size.1, (1, 1));
+        write!(
+            stdout,
+            "{}{}/{}{}",
+            cursor::Goto(2, 2),
+            Bold,
+            NoBold,
+            self.query
+        )
+        .unwrap();
+        match self.error {
+            // Point at where in the query it went wrong.
+            Some(ref error) => {
+                let x = error.position.min(self.query.chars().count()) + 3;
+                write!(
+                    stdout,
+                    "{}^{}{}",
+                    cursor::Goto(x as u16, 3),
+                    cursor::Goto(2, 4),
+                    error.message
+                )
+                .unwrap();
+            }
+            None if !self.query.is_empty() && self.results.is_empty() => {
+                write!(stdout, "{}Nothing found.", cursor::Goto(2, 4)).unwrap();
+            }
+            None => {}
+        }
+
+        // The first row holds the prompt and the second is left empty.
+        let height = (size.1 as usize).saturating_sub(2).max(1);
+        let rows = self.rows();
+        let selected_row = rows
+            .iter()
+            .position(|row| match row {
+                SearchRow::Result(index) => *index == self.selected,
+                SearchRow::Heading(_) => false,
+            })
+            .unwrap_or(0);
+        // Keep the heading of the first result in view.
+        if selected_row <= 1 {
+            self.reference = 0;
+        } else if selected_row < self.reference {
+            self.reference = selected_row;
+        } else if selected_row >= self.reference + height {
+            self.reference = selected_row + 1 - height;
+        }
+
+        let shown = rows.iter().enumerate().skip(self.reference);
+        for (line, (row_index, row)) in shown.take(height).enumerate() {
+            let mut text = match row {
+                SearchRow::Heading(heading) => heading.to_string(),
+                SearchRow::Result(index) => {
+                    match result_label(library, &self.results[*index]) {
+                        Some(label) => format!("    {}", label),
+                        None => continue,
+                    }
+                }
+            };
+            if text.chars().count() > width {
+                text = text.chars().take(width.saturating_sub(2)).collect();
+                text.push_str("..");
+            }
+            write!(stdout, "{}", cursor::Goto(2, line as u16 + 4)).unwrap();
+            match row {
+                SearchRow::Heading(_) => {
+                    write!(stdout, "{}{}{}", Bold, text, Reset).unwrap()
+                }
+                SearchRow::Result(_) if row_index == selected_row => {
+                    write!(stdout, "{}{}{}", Invert, text, NoInvert).unwrap()
+                }
+                SearchRow::Result(_) => write!(stdout, "{}", text).unwrap(),
+            }
+        }
+    }
+}
+
+// What a search result is shown as. None if the library changed and it is
+// no longer there.
+fn result_label(library: &Library, result: &SearchResult) -> Option<String> {
+    match *result {
+        SearchResult::Artist(index) => {
+            let artist = library.artists().get(index)?;
+            return Some(artist.name.to_string());
+        }
+        SearchResult::Album(index) => {
+            let album = library.albums().get(index)?;
+            let mut label = format!(
+                "{} \u{2013} {}",
+                album.title,
+                album.artists.join(", ")
+            );
+            if album.year > 0 {
+                label.push_str(&format!(" ({})", album.year));
+            }
+            return Some(label);
+        }
+        SearchResult::Song(id) => {
+            let song = library.song(id)?;
+            return Some(format!(
+                "{} \u{2013} {}, {}",
+                song.title, song.artist, song.album
+            ));
+        }
+        SearchResult::Songs(ref label, _) => return Some(label.clone()),
+    }
+}
+
 // Draw border for screen.
 pub fn draw_box(
     stdout: &mut RawTerminal<Stdout>,
@@ -389,6 +957,106 @@ pub fn draw_box(
     stdout.write(BOTTOM_RIGHT_CORNER.as_bytes()).unwrap();
 }
 
+// Show the volume level in the top border, at the right edge of the screen.
+pub fn draw_volume(
+    stdout: &mut RawTerminal<Stdout>,
+    volume: u8,
+    muted: bool,
+    size: (u16, u16),
+) {
+    let label = if muted {
+        " vol muted ".to_string()
+    } else {
+        format!(" vol {:>3}% ", volume)
+    };
+    let x = size.0 + 1 - label.chars().count() as u16;
+    write!(stdout, "{}{}", cursor::Goto(x, 1), label).unwrap();
+}
+
+// One line summary of playback: state, song, time, queue position and
+// play modes.
+pub fn status_line(
+    player: &Player,
+    queue: &Queue,
+    scanning: Option<&ScanProgress>,
+    width: u16,
+) -> String {
+    let state = if player.is_stopped() {
+        player.error().unwrap_or("Stopped")
+    } else if player.is_paused() {
+        "Paused"
+    } else {
+        "Playing"
+    };
+    let mut line = match queue.current() {
+        Some(song) => {
+            let elapsed = if player.is_stopped() {
+                Duration::new(0, 0)
+            } else {
+                player.elapsed()
+            };
+            let total = match song.duration {
+                Some(duration) => format_duration(duration),
+                None => "-:--".to_string(),
+            };
+            format!(
+                " {}: {} \u{2013} {}  {} / {}  [{}/{}]",
+                state,
+                song.artist,
+                song.title,
+                format_duration(elapsed),
+                total,
+                queue.position().unwrap_or(0) + 1,
+                queue.len()
+            )
+        }
+        None => format!(" {}", state),
+    };
+    if let Some(progress) = scanning {
+        line.push_str(&format!("  {}", progress.status()));
+    }
+    if queue.shuffle() != Shuffle::Off {
+        line.push_str(&format!("  shuffle {}", queue.shuffle().name()));
+    }
+    if queue.repeat() != Repeat::Off {
+        line.push_str(&format!("  repeat {}", queue.repeat().name()));
+    }
+    if line.chars().count() > width as usize {
+        line = line.chars().take(width as usize - 2).collect();
+        line.push_str("..");
+    }
+    return line;
+}
+
+// Draw the status line on the row below the panes.
+pub fn draw_status_bar(
+    stdout: &mut RawTerminal<Stdout>,
+    line: &str,
+    size: (u16, u16),
+) {
+    write!(
+        stdout,
+        "{}{}{}",
+        cursor::Goto(1, size.1 + 3),
+        termion::clear::CurrentLine,
+        line
+    )
+    .unwrap();
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        return format!(
+            "{}:{:02}:{:02}",
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        );
+    }
+    return format!("{}:{:02}", secs / 60, secs % 60);
+}
+
 pub fn clear_area(
     stdout: &mut RawTerminal<Stdout>,
     width: u16,
@@ -403,3 +1071,37 @@ pub fn clear_area(
         write!(stdout, "{}", cursor::Goto(pos.0, pos.1 + y)).unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::test_song;
+
+    #[test]
+    fn keeps_highlight_when_an_artist_above_goes() {
+        let songs: Vec<Song> = (0..100)
+            .map(|artist| {
+                let name = format!("Artist {:03}", artist);
+                let path = format!("/music/{}/01.flac", name);
+                test_song(&name, "Album", "Song", &path)
+            })
+            .collect();
+        let mut library =
+            Library::new(songs.clone(), "Various Artists".to_string());
+        let size = (100, 40);
+        let mut pane = Pane::init_artist_pane(&library, size);
+        for _ in 0..98 {
+            pane.move_down(&library, size);
+        }
+        assert_eq!(pane.get_selected(), "Artist 098");
+        let cursors = pane.get_cursors();
+
+        library.swap_songs(&[songs[10].id], Vec::new());
+        let mut pane = Pane::init_artist_pane(&library, size);
+        pane.set_cursors(&cursors, &library, size);
+
+        assert_eq!(pane.get_selected(), "Artist 098");
+        assert!(pane.reference + size.1 as usize <= pane.options.len());
+        assert_eq!(pane.cursor_pos, cursors[0].cursor_pos);
+    }
+}
@@ -0,0 +1,565 @@
+use std::collections::VecDeque;
+use std::f32::consts::FRAC_PI_2;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rodio::source::UniformSourceIterator;
+use rodio::{Device, Sink, Source};
+
+use crate::decode::{self, AudioSource};
+use crate::events::Event;
+use crate::metadata::{AlbumKey, Song, SongId};
+
+const VOLUME_STEP: u8 = 5;
+
+// How much of the next song is decoded before the current one ends.
+const PREFETCH: Duration = Duration::from_secs(1);
+
+// The next song with its queue index, waiting for the chain to reach it.
+type Upcoming = Arc<Mutex<Option<(usize, TrackSource)>>>;
+
+// Where the audio thread is, shared between the player, the chain it has
+// handed to the sink and the playback monitor.
+struct Progress {
+    // Bumped for every chain started, so one left over from an earlier sink
+    // can't report on the current one.
+    generation: AtomicUsize,
+    // Queue index of the song being played.
+    index: AtomicUsize,
+    // Samples of the song played so far, counting every channel.
+    samples: AtomicUsize,
+    // Samples per second of the output, counting every channel.
+    rate: AtomicUsize,
+    // Set when the chain has moved on to the next song by itself.
+    changed: AtomicBool,
+    // Set once the chain has nothing left to play.
+    finished: AtomicBool,
+}
+
+// Owns the output device and the sink playing on it. Rodio sinks can't be
+// restarted once stopped, so stopping or seeking swaps in a new one.
+//
+// The sink only ever holds one chain, which plays the current song and then
+// goes straight on to the next one, opened and partly decoded in advance.
+// Appending songs to the sink instead leaves an audible gap between them.
+pub struct Player {
+    device: Device,
+    sink: Sink,
+    progress: Arc<Progress>,
+    upcoming: Upcoming,
+    // Queue index and ID of the song in `upcoming`.
+    upcoming_song: Option<(usize, SongId)>,
+    // Overlap between songs from different albums.
+    crossfade: Duration,
+    stopped: bool,
+    // Percent of full volume.
+    volume: u8,
+    muted: bool,
+    // Why the last song asked for couldn't be played.
+    error: Option<String>,
+}
+
+impl Player {
+    pub fn new(crossfade: Duration) -> Player {
+        let device = rodio::default_output_device().unwrap();
+        let sink = Sink::new(&device);
+        return Player {
+            device: device,
+            sink: sink,
+            progress: Arc::new(Progress {
+                generation: AtomicUsize::new(0),
+                index: AtomicUsize::new(0),
+                samples: AtomicUsize::new(0),
+                rate: AtomicUsize::new(0),
+                changed: AtomicBool::new(false),
+                finished: AtomicBool::new(false),
+            }),
+            upcoming: Arc::new(Mutex::new(None)),
+            upcoming_song: None,
+            crossfade: crossfade,
+            stopped: true,
+            volume: load_volume(),
+            muted: false,
+            error: None,
+        };
+    }
+
+    // Play `song`, beginning `start` into it. `index` is its queue index.
+    pub fn play(&mut self, song: &Song, index: usize, start: Duration) {
+        self.start(song, index, start, false);
+    }
+
+    // Get `song` ready to play from `start`, but paused.
+    pub fn cue(&mut self, song: &Song, index: usize, start: Duration) {
+        self.start(song, index, start, true);
+    }
+
+    fn start(
+        &mut self,
+        song: &Song,
+        index: usize,
+        start: Duration,
+        paused: bool,
+    ) {
+        let source = match TrackSource::new(song, start) {
+            Ok(source) => source,
+            Err(reason) => {
+                self.stop();
+                self.error =
+                    Some(format!("Can't play {}: {}", song.title, reason));
+                return;
+            }
+        };
+        self.error = None;
+        self.new_sink();
+        // Paused before anything is appended, so not a sample gets out.
+        if paused {
+            self.sink.pause();
+        }
+        let generation =
+            self.progress.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        // Rodio converts whatever it's given to the device's format anyway.
+        let output = self.device.default_output_format();
+        let (channels, sample_rate) = match output {
+            Ok(format) => (format.channels, format.sample_rate.0),
+            Err(_) => (source.channels(), source.sample_rate()),
+        };
+        let rate = channels as usize * sample_rate as usize;
+        let played = duration_to_samples(source.start, sample_rate, channels);
+        self.progress.index.store(index, Ordering::SeqCst);
+        self.progress.rate.store(rate, Ordering::SeqCst);
+        self.progress.samples.store(played, Ordering::SeqCst);
+        self.progress.changed.store(false, Ordering::SeqCst);
+        self.progress.finished.store(false, Ordering::SeqCst);
+        self.stopped = false;
+        self.sink.append(Chain {
+            current: Track::new(source, channels, sample_rate),
+            incoming: None,
+            played: played,
+            fade: duration_to_samples(self.crossfade, sample_rate, channels),
+            upcoming: self.upcoming.clone(),
+            channels: channels,
+            sample_rate: sample_rate,
+            generation: generation,
+            progress: self.progress.clone(),
+        });
+    }
+
+    // Get the song that should follow the current one ready, so the chain
+    // can go straight into it. Does nothing if it's already waiting.
+    pub fn preload(&mut self, next: Option<(usize, &Song)>) {
+        let wanted = next.map(|(index, song)| (index, song.id));
+        let waiting = self.upcoming.lock().unwrap().is_some();
+        if waiting && wanted == self.upcoming_song {
+            return;
+        }
+        // Open it before taking the lock, the audio thread may need it.
+        // One that can't be opened is left for `play` to report.
+        let source = next.and_then(|(index, song)| {
+            let mut source =
+                TrackSource::new(song, Duration::new(0, 0)).ok()?;
+            source.prefetch(PREFETCH);
+            return Some((index, source));
+        });
+        *self.upcoming.lock().unwrap() = source;
+        self.upcoming_song = wanted;
+    }
+
+    // The queue index of the playing song changed because the queue was
+    // edited.
+    pub fn set_index(&self, index: usize) {
+        self.progress.index.store(index, Ordering::SeqCst);
+    }
+
+    // Restart playback at `position` into the song, keeping the paused
+    // state.
+    pub fn seek(&mut self, song: &Song, index: usize, position: Duration) {
+        let paused = self.is_paused();
+        self.start(song, index, position, paused);
+    }
+
+    pub fn toggle_pause(&mut self) {
+        if self.sink.is_paused() {
+            self.sink.play();
+        } else {
+            self.sink.pause();
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.new_sink();
+        self.stopped = true;
+    }
+
+    pub fn volume_up(&mut self) {
+        self.set_volume(self.volume.saturating_add(VOLUME_STEP).min(100));
+    }
+
+    pub fn volume_down(&mut self) {
+        self.set_volume(self.volume.saturating_sub(VOLUME_STEP));
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self.muted = !self.muted;
+        self.apply_volume();
+    }
+
+    pub fn volume(&self) -> u8 {
+        return self.volume;
+    }
+
+    pub fn is_muted(&self) -> bool {
+        return self.muted;
+    }
+
+    // Changing the volume also unmutes, and the new level is saved so the
+    // next start picks it up.
+    fn set_volume(&mut self, volume: u8) {
+        self.volume = volume;
+        self.muted = false;
+        self.apply_volume();
+        save_volume(volume);
+    }
+
+    fn apply_volume(&mut self) {
+        if self.muted {
+            self.sink.set_volume(0.0);
+        } else {
+            self.sink.set_volume(self.volume as f32 / 100.0);
+        }
+    }
+
+    // Start the playback monitor, which tells the main loop whenever the
+    // chain moves on to the next song or runs out of songs.
+    pub fn watch(&self, events: Sender<Event>) {
+        let progress = self.progress.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(50));
+            let mut sent = Ok(());
+            if progress.changed.swap(false, Ordering::SeqCst) {
+                let index = progress.index.load(Ordering::SeqCst);
+                sent = sent.and(events.send(Event::TrackChanged(index)));
+            }
+            if progress.finished.swap(false, Ordering::SeqCst) {
+                sent = sent.and(events.send(Event::TrackFinished));
+            }
+            if sent.is_err() {
+                return;
+            }
+        });
+    }
+
+    fn new_sink(&mut self) {
+        self.sink = Sink::new(&self.device);
+        self.apply_volume();
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        return self.error.as_ref().map(|error| error.as_ref());
+    }
+
+    pub fn is_paused(&self) -> bool {
+        return self.sink.is_paused();
+    }
+
+    // True when stopped by the user or when the song has been played.
+    pub fn is_stopped(&self) -> bool {
+        return self.stopped || self.sink.empty();
+    }
+
+    // How far into the current song playback is.
+    pub fn elapsed(&self) -> Duration {
+        let samples = self.progress.samples.load(Ordering::SeqCst);
+        let rate = self.progress.rate.load(Ordering::SeqCst);
+        return samples_to_duration(samples, rate);
+    }
+}
+
+fn volume_file_path() -> PathBuf {
+    let mut volume_path: PathBuf = dirs::config_dir().unwrap();
+    volume_path.push("rsmus/volume");
+    return volume_path;
+}
+
+// Last volume level set, or full volume if there isn't one.
+fn load_volume() -> u8 {
+    let mut volume_data = String::new();
+    match File::open(volume_file_path()) {
+        Ok(mut volume_file) => {
+            if volume_file.read_to_string(&mut volume_data).is_err() {
+                return 100;
+            }
+        }
+        Err(_) => return 100,
+    }
+    return volume_data.trim().parse::<u8>().unwrap_or(100).min(100);
+}
+
+fn save_volume(volume: u8) {
+    let volume_path = volume_file_path();
+    if let Some(dir) = volume_path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    if let Ok(mut volume_file) = File::create(volume_path) {
+        let _ = write!(volume_file, "{}", volume);
+    }
+}
+
+// The source the sink plays: the current song, followed without a break by
+// whatever is waiting in `upcoming` when it ends. With a crossfade set, a
+// song from another album starts that long before the end of the current
+// one and the two overlap. Every song is converted to the output device's
+// channels and sample rate, so whatever format the next one is in, the sink
+// never sees the format change.
+struct Chain {
+    current: Track,
+    // Next song, fading in over the end of the current one.
+    incoming: Option<Track>,
+    // Samples played of the song being reported, which is the incoming one
+    // during a fade.
+    played: usize,
+    // Length of a crossfade in samples, zero for none.
+    fade: usize,
+    upcoming: Upcoming,
+    channels: u16,
+    sample_rate: u32,
+    generation: usize,
+    progress: Arc<Progress>,
+}
+
+impl Chain {
+    // Whether the current song is close enough to its end to start fading
+    // into the next one.
+    fn fade_due(&self) -> bool {
+        if self.fade == 0 || self.incoming.is_some() {
+            return false;
+        }
+        match self.current.length {
+            Some(length) => return self.played + self.fade >= length,
+            None => return false,
+        }
+    }
+
+    // Take the next song if one is waiting. For a crossfade it's only taken
+    // if it's from a different album, songs of the same album run straight
+    // into each other.
+    fn take_upcoming(&mut self, crossfade: bool) -> Option<(usize, Track)> {
+        let mut upcoming = self.upcoming.lock().unwrap();
+        if crossfade {
+            let other_album = match *upcoming {
+                Some((_, ref source)) => source.album != self.current.album,
+                None => false,
+            };
+            if !other_album {
+                return None;
+            }
+        }
+        return upcoming.take().map(|(index, source)| {
+            (index, Track::new(source, self.channels, self.sample_rate))
+        });
+    }
+
+    // Report the song at `index` as the one now playing.
+    fn moved_on(&mut self, index: usize) {
+        self.played = 0;
+        self.progress.index.store(index, Ordering::SeqCst);
+        self.progress.samples.store(0, Ordering::SeqCst);
+        self.progress.changed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Iterator for Chain {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        // A chain from a replaced sink mustn't touch the progress or take
+        // the next song.
+        if self.progress.generation.load(Ordering::SeqCst) != self.generation {
+            return None;
+        }
+        if self.fade_due() {
+            if let Some((index, track)) = self.take_upcoming(true) {
+                self.incoming = Some(track);
+                self.moved_on(index);
+            }
+        }
+        if self.incoming.is_some() && self.played >= self.fade {
+            // Fade finished. Whatever is left of the old song would be
+            // silent, so drop it.
+            self.current = self.incoming.take().unwrap();
+        }
+        let sample = match self.incoming {
+            Some(ref mut incoming) => {
+                // Equal power curve, so the overlap doesn't dip in volume.
+                let t = self.played as f32 / self.fade as f32 * FRAC_PI_2;
+                let outgoing = self.current.samples.next().unwrap_or(0);
+                incoming.samples.next().map(|sample| {
+                    let mixed =
+                        outgoing as f32 * t.cos() + sample as f32 * t.sin();
+                    mixed.max(i16::MIN as f32).min(i16::MAX as f32) as i16
+                })
+            }
+            None => self.current.samples.next(),
+        };
+        if let Some(sample) = sample {
+            self.played += 1;
+            self.progress.samples.store(self.played, Ordering::Relaxed);
+            return Some(sample);
+        }
+        // The song ran out. If it was one fading in that was shorter than
+        // the fade, the old one goes with it.
+        if let Some(incoming) = self.incoming.take() {
+            self.current = incoming;
+        }
+        match self.take_upcoming(false) {
+            Some((index, track)) => {
+                self.current = track;
+                self.moved_on(index);
+                return self.next();
+            }
+            None => {
+                self.progress.finished.store(true, Ordering::SeqCst);
+                return None;
+            }
+        }
+    }
+}
+
+impl Source for Chain {
+    // The format never changes.
+    fn current_frame_len(&self) -> Option<usize> {
+        return None;
+    }
+
+    fn channels(&self) -> u16 {
+        return self.channels;
+    }
+
+    fn sample_rate(&self) -> u32 {
+        return self.sample_rate;
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        return None;
+    }
+}
+
+// A song converted to the chain's output format.
+struct Track {
+    samples: UniformSourceIterator<TrackSource, i16>,
+    // Length in output samples, going by the song's tags.
+    length: Option<usize>,
+    album: AlbumKey,
+}
+
+impl Track {
+    fn new(source: TrackSource, channels: u16, sample_rate: u32) -> Track {
+        let length = source.duration.map(|duration| {
+            duration_to_samples(duration, sample_rate, channels)
+        });
+        let album = source.album.clone();
+        return Track {
+            samples: UniformSourceIterator::new(source, channels, sample_rate),
+            length: length,
+            album: album,
+        };
+    }
+}
+
+// A decoded song that can start part way through.
+pub struct TrackSource {
+    decoder: AudioSource,
+    // Samples decoded ahead of time.
+    buffer: VecDeque<i16>,
+    // Where in the song the first sample is.
+    start: Duration,
+    duration: Option<Duration>,
+    album: AlbumKey,
+}
+
+impl TrackSource {
+    fn new(song: &Song, start: Duration) -> Result<TrackSource, String> {
+        let decoder = decode::open(Path::new(&song.path), start)?;
+        return Ok(TrackSource {
+            decoder: decoder,
+            buffer: VecDeque::new(),
+            start: start,
+            duration: song.duration,
+            album: song.album_key(),
+        });
+    }
+
+    // Decode the beginning of the song now, so starting it on the audio
+    // thread doesn't have to wait on the disk.
+    fn prefetch(&mut self, duration: Duration) {
+        let wanted = duration_to_samples(
+            duration,
+            self.decoder.sample_rate(),
+            self.decoder.channels(),
+        );
+        while self.buffer.len() < wanted {
+            match self.decoder.next() {
+                Some(sample) => self.buffer.push_back(sample),
+                None => break,
+            }
+        }
+    }
+}
+
+impl Iterator for TrackSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        match self.buffer.pop_front() {
+            Some(sample) => return Some(sample),
+            None => return self.decoder.next(),
+        }
+    }
+}
+
+impl Source for TrackSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        return self
+            .decoder
+            .current_frame_len()
+            .map(|len| len + self.buffer.len());
+    }
+
+    fn channels(&self) -> u16 {
+        return self.decoder.channels();
+    }
+
+    fn sample_rate(&self) -> u32 {
+        return self.decoder.sample_rate();
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        return self.decoder.total_duration();
+    }
+}
+
+// Whole frames only, so skipping never leaves the channels swapped.
+fn duration_to_samples(
+    duration: Duration,
+    sample_rate: u32,
+    channels: u16,
+) -> usize {
+    let frames = duration.as_secs() as usize * sample_rate as usize
+        + duration.subsec_nanos() as usize * sample_rate as usize
+            / 1_000_000_000;
+    return frames * channels as usize;
+}
+
+fn samples_to_duration(samples: usize, rate: usize) -> Duration {
+    if rate == 0 {
+        return Duration::new(0, 0);
+    }
+    let nanos = (samples % rate) as u64 * 1_000_000_000 / rate as u64;
+    return Duration::new((samples / rate) as u64, nanos as u32);
+}
@@ -0,0 +1,536 @@
+use crate::metadata::Song;
+
+// Queries pick out songs by their tags, e.g.
+//
+//     artist:"boards of canada" year:1995..2005 genre:ambient duration:>10m
+//
+// Every term has to match. A term is `field:value` or just a value, which
+// is looked for in the artist, album and title. Text fields match when they
+// contain the value, or are it with `field:=value`, in any case. Year, track
+// and duration take a number, `<`, `<=`, `>` or `>=` one, or a range
+// `from..to` with either end left off. Durations are seconds or written like
+// `10m`, `3m30s` or `3:30`. A `-` in front of a term leaves out the songs it
+// matches, and values with spaces go in double quotes.
+pub struct Query {
+    terms: Vec<Term>,
+}
+
+// Why a query couldn't be read, and where in it, counting characters.
+#[derive(Debug, PartialEq)]
+pub struct QueryError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl QueryError {
+    fn new(position: usize, message: String) -> QueryError {
+        return QueryError {
+            position: position,
+            message: message,
+        };
+    }
+}
+
+struct Term {
+    negated: bool,
+    test: Test,
+}
+
+enum Test {
+    // Text in any of the artist, album and title.
+    Any(String),
+    Contains(Field, String),
+    Is(Field, String),
+    Compare(Field, Comparison),
+}
+
+enum Comparison {
+    Equal(u64),
+    Less(u64),
+    AtMost(u64),
+    More(u64),
+    AtLeast(u64),
+    Between(u64, u64),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Field {
+    Artist,
+    Album,
+    Title,
+    Genre,
+    Path,
+    Year,
+    Track,
+    Duration,
+}
+
+const FIELDS: [(&str, Field); 8] = [
+    ("artist", Field::Artist),
+    ("album", Field::Album),
+    ("title", Field::Title),
+    ("genre", Field::Genre),
+    ("path", Field::Path),
+    ("year", Field::Year),
+    ("track", Field::Track),
+    ("duration", Field::Duration),
+];
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        let name = name.to_lowercase();
+        return FIELDS
+            .iter()
+            .find(|(field_name, _)| *field_name == name)
+            .map(|&(_, field)| field);
+    }
+
+    fn is_number(self) -> bool {
+        match self {
+            Field::Year | Field::Track | Field::Duration => return true,
+            _ => return false,
+        }
+    }
+
+    // Read a number for the field, with what it should have been if it
+    // isn't one.
+    fn number(self, text: &str) -> Result<u64, String> {
+        let (value, expected) = match self {
+            Field::Year => (text.parse().ok(), "a year"),
+            Field::Track => (text.parse().ok(), "a track number"),
+            _ => (parse_duration(text), "a duration, e.g. 90, 10m or 3:30"),
+        };
+        return value.ok_or(format!("\"{}\" isn't {}", text, expected));
+    }
+}
+
+impl Query {
+    pub fn matches(&self, song: &Song) -> bool {
+        return self
+            .terms
+            .iter()
+            .all(|term| term.test.matches(song) != term.negated);
+    }
+}
+
+impl Test {
+    fn matches(&self, song: &Song) -> bool {
+        match self {
+            Test::Any(text) => {
+                return [Field::Artist, Field::Album, Field::Title]
+                    .iter()
+                    .flat_map(|&field| texts(song, field))
+                    .any(|value| value.to_lowercase().contains(text));
+            }
+            Test::Contains(field, text) => {
+                return texts(song, *field)
+                    .iter()
+                    .any(|value| value.to_lowercase().contains(text));
+            }
+            Test::Is(field, text) => {
+                return texts(song, *field)
+                    .iter()
+                    .any(|value| value.to_lowercase() == *text);
+            }
+            Test::Compare(field, comparison) => match number(song, *field) {
+                Some(value) => return comparison.matches(value),
+                None => return false,
+            },
+        }
+    }
+}
+
+impl Comparison {
+    fn matches(&self, value: u64) -> bool {
+        match *self {
+            Comparison::Equal(wanted) => return value == wanted,
+            Comparison::Less(limit) => return value < limit,
+            Comparison::AtMost(limit) => return value <= limit,
+            Comparison::More(limit) => return value > limit,
+            Comparison::AtLeast(limit) => return value >= limit,
+            Comparison::Between(from, to) => {
+                return from <= value && value <= to;
+            }
+        }
+    }
+}
+
+// The song's text for a text field. Artist is the album artist as well as
+// the song's own, so a query for an artist finds their whole albums.
+fn texts(song: &Song, field: Field) -> Vec<&str> {
+    match field {
+        Field::Artist => {
+            let mut texts = vec![song.artist.as_str()];
+            match song.album_artist {
+                Some(ref artist) => texts.push(artist),
+                None => {}
+            }
+            return texts;
+        }
+        Field::Album => return vec![&song.album],
+        Field::Title => return vec![&song.title],
+        Field::Genre => return vec![&song.genre],
+        Field::Path => return vec![&song.path],
+        _ => return Vec::new(),
+    }
+}
+
+// The song's number for a number field. None when it isn't tagged, which a
+// year or track of zero means.
+fn number(song: &Song, field: Field) -> Option<u64> {
+    match field {
+        Field::Year if song.year > 0 => return Some(song.year as u64),
+        Field::Track if song.track > 0 => return Some(song.track as u64),
+        Field::Duration => return song.duration.map(|time| time.as_secs()),
+        _ => return None,
+    }
+}
+
+// Seconds in a duration written as seconds, `1h2m3s` with any of the parts
+// left out, or `1:02:03` or `2:03`.
+fn parse_duration(text: &str) -> Option<u64> {
+    if text.is_empty() {
+        return None;
+    }
+    let mut seconds: u64 = 0;
+    if text.contains(':') {
+        let parts: Vec<&str> = text.split(':').collect();
+        if parts.len() > 3 {
+            return None;
+        }
+        for (n, part) in parts.iter().enumerate() {
+            let value: u64 = part.parse().ok()?;
+            if n > 0 && (value >= 60 || part.len() != 2) {
+                return None;
+            }
+            seconds = seconds.checked_mul(60)?.checked_add(value)?;
+        }
+        return Some(seconds);
+    }
+    let mut digits = String::new();
+    for c in text.chars() {
+        let unit = match c {
+            '0'..='9' => {
+                digits.push(c);
+                continue;
+            }
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+        let value: u64 = digits.parse().ok()?;
+        seconds = seconds.checked_add(value.checked_mul(unit)?)?;
+        digits.clear();
+    }
+    // A number with no unit after it is seconds, e.g. `3m30`.
+    if !digits.is_empty() {
+        seconds = seconds.checked_add(digits.parse().ok()?)?;
+    }
+    return Some(seconds);
+}
+
+// Whether `text` looks meant as a query rather than a fuzzy search, having a
+// `field:` term in it.
+pub fn is_query(text: &str) -> bool {
+    return text.split_whitespace().any(|word| {
+        let word = word.trim_start_matches('-');
+        match word.find(':') {
+            Some(colon) => Field::from_name(&word[..colon]).is_some(),
+            None => false,
+        }
+    });
+}
+
+pub fn parse(text: &str) -> Result<Query, QueryError> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        position: 0,
+    };
+    let mut terms = Vec::new();
+    loop {
+        parser.skip_spaces();
+        if parser.peek().is_none() {
+            break;
+        }
+        terms.push(parser.term()?);
+    }
+    if terms.is_empty() {
+        return Err(QueryError::new(0, "the query is empty".to_string()));
+    }
+    return Ok(Query { terms: terms });
+}
+
+struct Parser {
+    chars: Vec<char>,
+    // Index into `chars` of the next one to read.
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        return self.chars.get(self.position).cloned();
+    }
+
+    fn at_space(&self) -> bool {
+        match self.peek() {
+            Some(c) => return c.is_whitespace(),
+            None => return true,
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek().map_or(false, |c| c.is_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    // Skip `prefix` if it is next.
+    fn eat(&mut self, prefix: &str) -> bool {
+        let end = self.position + prefix.chars().count();
+        if end > self.chars.len() {
+            return false;
+        }
+        let next: String = self.chars[self.position..end].iter().collect();
+        if next == prefix {
+            self.position = end;
+            return true;
+        }
+        return false;
+    }
+
+    fn term(&mut self) -> Result<Term, QueryError> {
+        let start = self.position;
+        let negated = self.eat("-");
+        if negated && self.at_space() {
+            let message = "nothing after \"-\" to leave out".to_string();
+            return Err(QueryError::new(start, message));
+        }
+
+        let name_start = self.position;
+        let name_length = self.chars[name_start..]
+            .iter()
+            .take_while(|c| c.is_alphanumeric() || **c == '_')
+            .count();
+        let colon = self.chars.get(name_start + name_length) == Some(&':');
+        if name_length == 0 || !colon {
+            let (text, value_start) = self.value()?;
+            if text.is_empty() {
+                let message = "nothing between the quotes".to_string();
+                return Err(QueryError::new(value_start, message));
+            }
+            return Ok(Term {
+                negated: negated,
+                test: Test::Any(text.to_lowercase()),
+            });
+        }
+
+        let name: String = self.chars[name_start..name_start + name_length]
+            .iter()
+            .collect();
+        let field = match Field::from_name(&name) {
+            Some(field) => field,
+            None => {
+                let names: Vec<&str> =
+                    FIELDS.iter().map(|(name, _)| *name).collect();
+                let message = format!(
+                    "unknown field \"{}\", the fields are {}, or put text \
+                     with a \":\" in quotes",
+                    name,
+                    names.join(", ")
+                );
+                return Err(QueryError::new(name_start, message));
+            }
+        };
+        self.position = name_start + name_length + 1;
+        let test = if field.is_number() {
+            self.comparison(field, &name)?
+        } else {
+            let exact = self.eat("=");
+            let (text, value_start) = self.value()?;
+            if text.is_empty() {
+                let message = format!("nothing to match after \"{}:\"", name);
+                return Err(QueryError::new(value_start, message));
+            }
+            if exact {
+                Test::Is(field, text.to_lowercase())
+            } else {
+                Test::Contains(field, text.to_lowercase())
+            }
+        };
+        return Ok(Term {
+            negated: negated,
+            test: test,
+        });
+    }
+
+    // The value of a number field: a number, a comparison or a range.
+    fn comparison(
+        &mut self,
+        field: Field,
+        name: &str,
+    ) -> Result<Test, QueryError> {
+        let operator_start = self.position;
+        // Longest first, so `<=` isn't read as `<`.
+        let operators = [">=", "<=", ">", "<", "="];
+        let operator = operators.iter().find(|operator| self.eat(operator));
+        let (text, value_start) = self.value()?;
+        if text.is_empty() {
+            let message = format!("nothing to match after \"{}:\"", name);
+            return Err(QueryError::new(value_start, message));
+        }
+        let number = |text: &str, offset: usize| {
+            let position = value_start + offset;
+            return field
+                .number(text)
+                .map_err(|message| QueryError::new(position, message));
+        };
+
+        let comparison = match operator {
+            Some(&">=") => Comparison::AtLeast(number(&text, 0)?),
+            Some(&"<=") => Comparison::AtMost(number(&text, 0)?),
+            Some(&">") => Comparison::More(number(&text, 0)?),
+            Some(&"<") => Comparison::Less(number(&text, 0)?),
+            Some(_) => Comparison::Equal(number(&text, 0)?),
+            None => match text.find("..") {
+                None => Comparison::Equal(number(&text, 0)?),
+                Some(dots) => {
+                    let from = &text[..dots];
+                    let to = &text[dots + 2..];
+                    let to_offset = text[..dots + 2].chars().count();
+                    match (from.is_empty(), to.is_empty()) {
+                        (true, true) => {
+                            let message =
+                                "a range needs a number on at least one side"
+                                    .to_string();
+                            return Err(QueryError::new(value_start, message));
+                        }
+                        (true, false) => {
+                            Comparison::AtMost(number(to, to_offset)?)
+                        }
+                        (false, true) => Comparison::AtLeast(number(from, 0)?),
+                        (false, false) => {
+                            let from = number(from, 0)?;
+                            let to = number(to, to_offset)?;
+                            if from > to {
+                                let message = format!(
+                                    "the range {} is empty, the smaller \
+                                     number goes first",
+                                    text
+                                );
+                                return Err(QueryError::new(
+                                    operator_start,
+                                    message,
+                                ));
+                            }
+                            Comparison::Between(from, to)
+                        }
+                    }
+                }
+            },
+        };
+        return Ok(Test::Compare(field, comparison));
+    }
+
+    // A value in double quotes, or everything up to the next space, and
+    // where it starts.
+    fn value(&mut self) -> Result<(String, usize), QueryError> {
+        let start = self.position;
+        if !self.eat("\"") {
+            let mut text = String::new();
+            while !self.at_space() {
+                text.push(self.chars[self.position]);
+                self.position += 1;
+            }
+            return Ok((text, start));
+        }
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                None => {
+                    let message = "the quote is never closed".to_string();
+                    return Err(QueryError::new(start, message));
+                }
+                Some('"') => break,
+                // A backslash lets a quote or backslash into the text.
+                Some('\\') if self.chars.get(self.position + 1).is_some() => {
+                    text.push(self.chars[self.position + 1]);
+                    self.position += 2;
+                }
+                Some(c) => {
+                    text.push(c);
+                    self.position += 1;
+                }
+            }
+        }
+        self.position += 1;
+        if !self.at_space() {
+            let message = "expected a space after the closing quote".into();
+            return Err(QueryError::new(self.position, message));
+        }
+        return Ok((text, start + 1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::test_song;
+    use std::time::Duration;
+
+    fn song(artist: &str, title: &str, year: u32, seconds: u64) -> Song {
+        let path = format!("/music/{}/{}.flac", artist, title);
+        let album = "Music Has the Right to Children";
+        let mut song = test_song(artist, album, title, &path);
+        song.year = year;
+        song.duration = Some(Duration::from_secs(seconds));
+        song.genre = "Ambient".to_string();
+        return song;
+    }
+
+    fn matches(query: &str, song: &Song) -> bool {
+        return parse(query).unwrap().matches(song);
+    }
+
+    fn error(query: &str) -> QueryError {
+        match parse(query) {
+            Ok(_) => panic!("{} parsed", query),
+            Err(error) => return error,
+        }
+    }
+
+    #[test]
+    fn matches_fields() {
+        let long = song("Boards of Canada", "Olson", 1998, 660);
+        let short = song("Boards of Canada", "Roygbiv", 1998, 151);
+        let query = "artist:\"boards of canada\" year:1995..2005 \
+                     genre:ambient duration:>10m";
+        assert!(matches(query, &long));
+        assert!(!matches(query, &short));
+        assert!(matches("roygbiv duration:2:31", &short));
+        assert!(matches("-title:olson year:>=1998", &short));
+        assert!(!matches("artist:=boards", &short));
+        assert!(matches("duration:2m..3m path:roygbiv", &short));
+        assert!(!matches("year:..1997", &short));
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert_eq!(error("artist:\"boards").position, 7);
+        assert_eq!(error("artst:air").position, 0);
+        assert_eq!(error("year:19x5").message, "\"19x5\" isn't a year");
+        assert_eq!(error("year:2005..1995").position, 5);
+        assert_eq!(error("duration:1..soon").position, 12);
+        assert_eq!(
+            error("title:").message,
+            "nothing to match after \"title:\""
+        );
+        assert_eq!(error("  ").message, "the query is empty");
+    }
+
+    #[test]
+    fn tells_queries_from_searches() {
+        assert!(is_query("boards -genre:ambient"));
+        assert!(!is_query("re:stacks"));
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::metadata::{AlbumKey, Song};
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Shuffle {
+    Off,
+    // Every song in a random place.
+    Tracks,
+    // Albums in a random order, each one played through in queue order.
+    Albums,
+}
+
+impl Shuffle {
+    pub fn cycle(self) -> Shuffle {
+        match self {
+            Shuffle::Off => return Shuffle::Tracks,
+            Shuffle::Tracks => return Shuffle::Albums,
+            Shuffle::Albums => return Shuffle::Off,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Shuffle::Off => return "off",
+            Shuffle::Tracks => return "tracks",
+            Shuffle::Albums => return "albums",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Repeat {
+    Off,
+    One,
+    Album,
+    All,
+}
+
+impl Repeat {
+    pub fn cycle(self) -> Repeat {
+        match self {
+            Repeat::Off => return Repeat::One,
+            Repeat::One => return Repeat::Album,
+            Repeat::Album => return Repeat::All,
+            Repeat::All => return Repeat::Off,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Repeat::Off => return "off",
+            Repeat::One => return "one",
+            Repeat::Album => return "album",
+            Repeat::All => return "all",
+        }
+    }
+}
+
+// Ordered list of songs to play. The queue owns its songs so it stays valid
+// no matter where the panes are pointing.
+pub struct Queue {
+    songs: Vec<Song>,
+    // Order the songs are played in, as indices into `songs`. Shuffling
+    // only changes this, so the play order stays fixed until the queue or
+    // the shuffle mode changes and previous really goes back.
+    order: Vec<usize>,
+    // Index into `songs` of the current song.
+    current: Option<usize>,
+    shuffle: Shuffle,
+    repeat: Repeat,
+    rng: Rng,
+}
+
+impl Queue {
+    pub fn new() -> Queue {
+        return Queue {
+            songs: Vec::new(),
+            order: Vec::new(),
+            current: None,
+            shuffle: Shuffle::Off,
+            repeat: Repeat::Off,
+            rng: Rng::new(),
+        };
+    }
+
+    // Replace the whole queue, making the song at `start` current.
+    pub fn replace(&mut self, songs: Vec<Song>, start: usize) {
+        self.current = if start < songs.len() {
+            Some(start)
+        } else {
+            None
+        };
+        self.songs = songs;
+        self.reorder();
+    }
+
+    // Add songs to the end of the queue. If nothing was current the first
+    // added song becomes current.
+    pub fn append(&mut self, songs: Vec<Song>) {
+        let start = self.songs.len();
+        if self.current.is_none() && !songs.is_empty() {
+            self.current = Some(start);
+        }
+        self.songs.extend(songs);
+        let added = (start..self.songs.len()).collect();
+        let groups = self.shuffled_groups(added);
+        for group in groups {
+            self.order.extend(group);
+        }
+    }
+
+    // Put back a queue saved by an earlier session. An order that doesn't
+    // fit the songs is rebuilt.
+    pub fn restore(
+        &mut self,
+        songs: Vec<Song>,
+        order: Vec<usize>,
+        current: Option<usize>,
+        shuffle: Shuffle,
+        repeat: Repeat,
+    ) {
+        self.shuffle = shuffle;
+        self.repeat = repeat;
+        self.current = current.filter(|&index| index < songs.len());
+        self.songs = songs;
+        let mut sorted = order.clone();
+        sorted.sort();
+        if sorted == (0..self.songs.len()).collect::<Vec<usize>>() {
+            self.order = order;
+        } else {
+            self.reorder();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.songs.clear();
+        self.order.clear();
+        self.current = None;
+    }
+
+    pub fn current(&self) -> Option<&Song> {
+        match self.current {
+            Some(index) => return self.songs.get(index),
+            None => return None,
+        }
+    }
+
+    pub fn songs(&self) -> &[Song] {
+        return &self.songs;
+    }
+
+    pub fn order(&self) -> &[usize] {
+        return &self.order;
+    }
+
+    pub fn position(&self) -> Option<usize> {
+        return self.current;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.songs.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.songs.is_empty();
+    }
+
+    pub fn shuffle(&self) -> Shuffle {
+        return self.shuffle;
+    }
+
+    pub fn repeat(&self) -> Repeat {
+        return self.repeat;
+    }
+
+    pub fn cycle_shuffle(&mut self) {
+        self.shuffle = self.shuffle.cycle();
+        self.reorder();
+    }
+
+    pub fn cycle_repeat(&mut self) {
+        self.repeat = self.repeat.cycle();
+    }
+
+    // Move to the next song in play order, wrapping round if repeating.
+    // Stays on the last song if there is none.
+    pub fn next(&mut self) -> Option<&Song> {
+        let index = self.next_index()?;
+        self.current = Some(index);
+        return self.current();
+    }
+
+    // Move on once the current song has finished. Unlike `next` this keeps
+    // to the same song when repeating one.
+    pub fn advance(&mut self) -> Option<&Song> {
+        if self.repeat == Repeat::One {
+            return self.current();
+        }
+        return self.next();
+    }
+
+    // The song `advance` would move to, with its index, so the player can
+    // get it ready before the current one ends.
+    pub fn peek_advance(&self) -> Option<(usize, &Song)> {
+        let index = if self.repeat == Repeat::One {
+            self.current?
+        } else {
+            self.next_index()?
+        };
+        return Some((index, &self.songs[index]));
+    }
+
+    // Move to the previous song in play order, wrapping round if repeating.
+    // Stays on the first song if there is none.
+    pub fn previous(&mut self) -> Option<&Song> {
+        let position = self.order_position()?;
+        let len = self.order.len();
+        let previous = match self.repeat {
+            Repeat::Album => Some(self.step_in_album(position, false)),
+            Repeat::All => Some((position + len - 1) % len),
+            _ if position > 0 => Some(position - 1),
+            _ => None,
+        };
+        match previous {
+            Some(previous) => self.current = Some(self.order[previous]),
+            None => return None,
+        }
+        return self.current();
+    }
+
+    // Swap the song at `index` with the one above it. Returns whether it
+    // moved.
+    pub fn move_up(&mut self, index: usize) -> bool {
+        if index == 0 || index >= self.songs.len() {
+            return false;
+        }
+        self.swap(index - 1, index);
+        return true;
+    }
+
+    // Swap the song at `index` with the one below it. Returns whether it
+    // moved.
+    pub fn move_down(&mut self, index: usize) -> bool {
+        if index + 1 >= self.songs.len() {
+            return false;
+        }
+        self.swap(index, index + 1);
+        return true;
+    }
+
+    // Take the song at `index` out of the queue. If it was the current one,
+    // the song after it in play order becomes current.
+    pub fn remove(&mut self, index: usize) {
+        if index >= self.songs.len() {
+            return;
+        }
+        let position =
+            self.order.iter().position(|&song| song == index).unwrap();
+        let was_current = self.current == Some(index);
+        self.songs.remove(index);
+        self.order.remove(position);
+        for song in self.order.iter_mut() {
+            if *song > index {
+                *song -= 1;
+            }
+        }
+        if was_current {
+            self.current = self.order.get(position).cloned();
+        } else if let Some(current) = self.current {
+            if current > index {
+                self.current = Some(current - 1);
+            }
+        }
+    }
+
+    // Point the cursor at the song at `index`, e.g. the one the player has
+    // moved on to by itself.
+    pub fn set_position(&mut self, index: usize) {
+        if index < self.songs.len() {
+            self.current = Some(index);
+        }
+    }
+
+    // Index of the song after the current one in play order.
+    fn next_index(&self) -> Option<usize> {
+        let position = self.order_position()?;
+        let next = match self.repeat {
+            Repeat::Album => self.step_in_album(position, true),
+            Repeat::All => (position + 1) % self.order.len(),
+            _ if position + 1 < self.order.len() => position + 1,
+            _ => return None,
+        };
+        return Some(self.order[next]);
+    }
+
+    // Swap two songs in the queue. Unshuffled, the play order follows the
+    // queue so it changes with it; shuffled, it stays as it was.
+    fn swap(&mut self, a: usize, b: usize) {
+        let swapped = |index: usize| {
+            if index == a {
+                b
+            } else if index == b {
+                a
+            } else {
+                index
+            }
+        };
+        self.songs.swap(a, b);
+        self.current = self.current.map(swapped);
+        if self.shuffle == Shuffle::Off {
+            self.order = (0..self.songs.len()).collect();
+        } else {
+            for song in self.order.iter_mut() {
+                *song = swapped(*song);
+            }
+        }
+    }
+
+    fn order_position(&self) -> Option<usize> {
+        match self.current {
+            Some(index) => {
+                return self.order.iter().position(|&song| song == index);
+            }
+            None => return None,
+        }
+    }
+
+    // Play order position of the next (or previous) song from the same
+    // album as the one at `position`, wrapping round.
+    fn step_in_album(&self, position: usize, forward: bool) -> usize {
+        let len = self.order.len();
+        let album = self.songs[self.order[position]].album_key();
+        for step in 1..len {
+            let candidate = if forward {
+                (position + step) % len
+            } else {
+                (position + len - step) % len
+            };
+            if self.songs[self.order[candidate]].album_key() == album {
+                return candidate;
+            }
+        }
+        return position;
+    }
+
+    // Rebuild the play order for the current shuffle mode, with the group
+    // holding the current song played first.
+    fn reorder(&mut self) {
+        let all = (0..self.songs.len()).collect();
+        let mut groups = self.shuffled_groups(all);
+        if let Some(current) = self.current {
+            if let Some(first) =
+                groups.iter().position(|group| group.contains(&current))
+            {
+                let group = groups.remove(first);
+                groups.insert(0, group);
+            }
+        }
+        self.order = groups.concat();
+    }
+
+    // Split `indices` into the groups that stay together when shuffling and
+    // put the groups in play order.
+    fn shuffled_groups(&mut self, indices: Vec<usize>) -> Vec<Vec<usize>> {
+        match self.shuffle {
+            Shuffle::Off => return vec![indices],
+            Shuffle::Tracks => {
+                let mut groups: Vec<Vec<usize>> =
+                    indices.into_iter().map(|index| vec![index]).collect();
+                self.rng.shuffle(&mut groups);
+                return groups;
+            }
+            Shuffle::Albums => {
+                let mut groups: Vec<Vec<usize>> = Vec::new();
+                let mut albums: HashMap<AlbumKey, usize> = HashMap::new();
+                for index in indices {
+                    let album = self.songs[index].album_key();
+                    match albums.get(&album) {
+                        Some(&group) => groups[group].push(index),
+                        None => {
+                            albums.insert(album, groups.len());
+                            groups.push(vec![index]);
+                        }
+                    }
+                }
+                self.rng.shuffle(&mut groups);
+                return groups;
+            }
+        }
+    }
+}
+
+// Small xorshift generator, seeded once per session. Pulling in a crate for
+// shuffling a play queue isn't worth it.
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new() -> Rng {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs() ^ time.subsec_nanos() as u64)
+            .unwrap_or(0);
+        // Xorshift gets stuck on zero.
+        return Rng { state: seed | 1 };
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        return self.state;
+    }
+
+    // Fisher-Yates shuffle.
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = (self.next() % (i as u64 + 1)) as usize;
+            items.swap(i, j);
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+
+use crate::library::Library;
+use crate::metadata::{Song, SongId};
+use crate::query::{self, QueryError};
+
+// Most results of each kind that are listed.
+const MAX_ARTISTS: usize = 5;
+const MAX_ALBUMS: usize = 10;
+const MAX_SONGS: usize = 50;
+// Most songs a query lists one by one. All of them can still be queued.
+const MAX_MATCHES: usize = 500;
+
+// Extra score for a match at the start of a word, and for one straight
+// after the previous match.
+const WORD_START: i64 = 8;
+const ADJACENT: i64 = 5;
+// Most a gap between two matches costs.
+const MAX_GAP: usize = 5;
+
+#[derive(Clone, PartialEq)]
+pub enum SearchResult {
+    // Index into the library's artists.
+    Artist(usize),
+    // Index into the library's albums.
+    Album(usize),
+    Song(SongId),
+    // What a query or smart playlist matched, with what it is listed as.
+    Songs(String, Vec<SongId>),
+}
+
+impl SearchResult {
+    // Songs to queue for the result: every album of an artist, a whole
+    // album or the song itself.
+    pub fn songs(&self, library: &Library) -> Vec<Song> {
+        let album_songs = |index: usize| match library.albums().get(index) {
+            Some(album) => library.album_songs(album),
+            None => Vec::new(),
+        };
+        let songs = match *self {
+            SearchResult::Artist(index) => match library.artists().get(index) {
+                Some(artist) => artist
+                    .albums
+                    .iter()
+                    .flat_map(|&album| album_songs(album))
+                    .collect(),
+                None => Vec::new(),
+            },
+            SearchResult::Album(index) => album_songs(index),
+            SearchResult::Song(id) => library.song(id).into_iter().collect(),
+            SearchResult::Songs(_, ref ids) => {
+                ids.iter().filter_map(|&id| library.song(id)).collect()
+            }
+        };
+        return songs.into_iter().cloned().collect();
+    }
+
+    // Where the result is in the library panes: its position in the artist
+    // pane, then in the artist's albums, then in the album.
+    pub fn location(&self, library: &Library) -> Option<Vec<usize>> {
+        match *self {
+            SearchResult::Artist(index) => return Some(vec![index]),
+            SearchResult::Album(index) => {
+                let album = library.albums().get(index)?;
+                // Under the first album artist, as it would be found by hand.
+                let artist = library.artist_index(album.artists.first()?)?;
+                let position = library.artists()[artist]
+                    .albums
+                    .iter()
+                    .position(|&listed| listed == index)?;
+                return Some(vec![artist, position]);
+            }
+            SearchResult::Song(id) => {
+                let index = library.album_of(id)?;
+                let mut location =
+                    SearchResult::Album(index).location(library)?;
+                let album = &library.albums()[index];
+                location.push(album.songs.iter().position(|&song| song == id)?);
+                return Some(location);
+            }
+            SearchResult::Songs(..) => return None,
+        }
+    }
+}
+
+// What `text` finds in the library. Nothing typed lists the smart
+// playlists, and a query lists what it matches. Anything else is a fuzzy
+// search.
+pub fn search(
+    library: &Library,
+    text: &str,
+    playlists: &BTreeMap<String, String>,
+) -> Result<Vec<SearchResult>, QueryError> {
+    if text.trim().is_empty() {
+        return Ok(smart_playlists(library, playlists));
+    }
+    if query::is_query(text) {
+        let query = query::parse(text)?;
+        let ids: Vec<SongId> = library
+            .find(|song| query.matches(song))
+            .iter()
+            .map(|song| song.id)
+            .collect();
+        let label = match ids.len() {
+            1 => "The 1 matching song".to_string(),
+            count => format!("All {} matching songs", count),
+        };
+        let mut results = Vec::new();
+        if !ids.is_empty() {
+            results.push(SearchResult::Songs(label, ids.clone()));
+        }
+        results
+            .extend(ids.into_iter().take(MAX_MATCHES).map(SearchResult::Song));
+        return Ok(results);
+    }
+    return Ok(fuzzy_search(library, text));
+}
+
+// Every smart playlist with the songs it has now. One whose query can't be
+// read is listed with why, and has no songs.
+fn smart_playlists(
+    library: &Library,
+    playlists: &BTreeMap<String, String>,
+) -> Vec<SearchResult> {
+    return playlists
+        .iter()
+        .map(|(name, text)| match query::parse(text) {
+            Ok(query) => {
+                let ids: Vec<SongId> = library
+                    .find(|song| query.matches(song))
+                    .iter()
+                    .map(|song| song.id)
+                    .collect();
+                let label = format!("{} ({} songs)", name, ids.len());
+                SearchResult::Songs(label, ids)
+            }
+            Err(error) => {
+                let label = format!("{}: {}", name, error.message);
+                SearchResult::Songs(label, Vec::new())
+            }
+        })
+        .collect();
+}
+
+// Artists, albums and songs whose names fuzzily match `query`, in that
+// order and each best first.
+fn fuzzy_search(library: &Library, query: &str) -> Vec<SearchResult> {
+    let pattern: Vec<char> = query.trim().to_lowercase().chars().collect();
+    if pattern.is_empty() {
+        return Vec::new();
+    }
+    let artists =
+        library
+            .artists()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, artist)| {
+                let score = fuzzy_score(&pattern, &artist.name)?;
+                return Some((
+                    score,
+                    &*artist.name,
+                    SearchResult::Artist(index),
+                ));
+            });
+    let albums =
+        library
+            .albums()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, album)| {
+                let score = fuzzy_score(&pattern, &album.title)?;
+                return Some((
+                    score,
+                    &*album.title,
+                    SearchResult::Album(index),
+                ));
+            });
+    let songs = library.songs().filter_map(|song| {
+        let score = fuzzy_score(&pattern, &song.title)?;
+        return Some((score, &*song.path, SearchResult::Song(song.id)));
+    });
+    let mut results = best(artists, MAX_ARTISTS);
+    results.extend(best(albums, MAX_ALBUMS));
+    results.extend(best(songs, MAX_SONGS));
+    return results;
+}
+
+// The `limit` highest scoring results. Equal scores are in order of the
+// text, so the same query always lists the same way.
+fn best<'a, I>(scored: I, limit: usize) -> Vec<SearchResult>
+where
+    I: Iterator<Item = (i64, &'a str, SearchResult)>,
+{
+    let mut scored: Vec<(i64, &str, SearchResult)> = scored.collect();
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+    return scored
+        .into_iter()
+        .take(limit)
+        .map(|(_, _, result)| result)
+        .collect();
+}
+
+// How well `pattern`, in lower case, matches `text`. Its characters have
+// to appear in `text` in order, but not next to each other. Matches at the
+// start of words and runs of them count for more, gaps and longer texts
+// for less. None if it doesn't match at all.
+pub fn fuzzy_score(pattern: &[char], text: &str) -> Option<i64> {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    if pattern.is_empty() || pattern.len() > text.len() {
+        return None;
+    }
+    // The first place the first character appears isn't always the best
+    // one to start from, e.g. "bo" in "a bob", so every one is tried.
+    let mut best = None;
+    for start in 0..text.len() {
+        if text[start] == pattern[0] {
+            best = best.max(score_from(pattern, &text, start));
+        }
+    }
+    return best;
+}
+
+fn score_from(pattern: &[char], text: &[char], start: usize) -> Option<i64> {
+    let mut score = 0;
+    let mut previous = start;
+    let mut position = start;
+    for (n, &wanted) in pattern.iter().enumerate() {
+        let found =
+            position + text[position..].iter().position(|&c| c == wanted)?;
+        score += 1;
+        if found == 0 || !text[found - 1].is_alphanumeric() {
+            score += WORD_START;
+        }
+        if n > 0 {
+            if found == previous + 1 {
+                score += ADJACENT;
+            } else {
+                score -= (found - previous - 1).min(MAX_GAP) as i64;
+            }
+        }
+        previous = found;
+        position = found + 1;
+    }
+    // Of two equally good matches, the shorter text is the closer one.
+    return Some(score * 100 - text.len().min(99) as i64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(pattern: &str, text: &str) -> Option<i64> {
+        let pattern: Vec<char> = pattern.chars().collect();
+        return fuzzy_score(&pattern, text);
+    }
+
+    #[test]
+    fn ranks_word_starts_first() {
+        assert!(score("bo", "the boat") > score("bo", "the kbot"));
+    }
+
+    #[test]
+    fn ranks_runs_above_gaps() {
+        assert!(score("ab", "abxx") > score("ab", "axbx"));
+    }
+
+    #[test]
+    fn ranks_shorter_text_first() {
+        assert!(score("air", "air") > score("air", "air supply"));
+    }
+
+    #[test]
+    fn finds_best_start() {
+        // "b" then "o" straight after, the second "b" being no better.
+        assert_eq!(score("bo", "a bob"), Some((1 + 8 + 1 + 5) * 100 - 5));
+        // The first "o" is mid-word, the second starts one.
+        assert!(score("o", "too old") == score("o", "xxx old"));
+    }
+
+    #[test]
+    fn needs_every_character_in_order() {
+        assert_eq!(score("ob", "bo"), None);
+        assert_eq!(score("abc", "ab"), None);
+        assert_eq!(score("xyz", "abc"), None);
+    }
+}
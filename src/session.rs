@@ -0,0 +1,103 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bincode::{deserialize, serialize};
+
+use crate::metadata::Song;
+use crate::panes::{Cursor, Pane};
+use crate::player::Player;
+use crate::queue::{Queue, Repeat, Shuffle};
+use crate::FocusedPane;
+
+// Playback state saved on quit and put back on the next start.
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    songs: Vec<Song>,
+    order: Vec<usize>,
+    current: Option<usize>,
+    elapsed: Duration,
+    shuffle: Shuffle,
+    repeat: Repeat,
+    focused_pane: FocusedPane,
+    cursors: Vec<Cursor>,
+}
+
+impl Session {
+    pub fn new(
+        queue: &Queue,
+        player: &Player,
+        focused_pane: &FocusedPane,
+        root_pane: &Pane,
+    ) -> Session {
+        let elapsed = if player.is_stopped() {
+            Duration::new(0, 0)
+        } else {
+            player.elapsed()
+        };
+        return Session {
+            songs: queue.songs().to_vec(),
+            order: queue.order().to_vec(),
+            current: queue.position(),
+            elapsed: elapsed,
+            shuffle: queue.shuffle(),
+            repeat: queue.repeat(),
+            focused_pane: focused_pane.clone(),
+            cursors: root_pane.get_cursors(),
+        };
+    }
+
+    // The session saved last time, if there is one that can be read.
+    pub fn load() -> Option<Session> {
+        let mut session_file = File::open(session_file_path()).ok()?;
+        let mut buffer = Vec::new();
+        session_file.read_to_end(&mut buffer).ok()?;
+        return deserialize(&buffer[..]).ok();
+    }
+
+    pub fn save(&self) {
+        let data: Vec<u8> = serialize(self).unwrap();
+        let session_path = session_file_path();
+        if let Some(dir) = session_path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        if let Ok(mut session_file) = File::create(session_path) {
+            let _ = session_file.write_all(&data);
+        }
+    }
+
+    // Put the queue and player back the way they were, and hand back where
+    // the panes were for when they are built. The current song is loaded
+    // paused at the point it was left.
+    pub fn restore(
+        self,
+        queue: &mut Queue,
+        player: &mut Player,
+        focused_pane: &mut FocusedPane,
+        cursors: &mut Vec<Cursor>,
+    ) {
+        queue.restore(
+            self.songs,
+            self.order,
+            self.current,
+            self.shuffle,
+            self.repeat,
+        );
+        if let (Some(song), Some(index)) = (queue.current(), queue.position()) {
+            // The file may have gone since.
+            if Path::new(&song.path).exists() {
+                player.cue(song, index, self.elapsed);
+                player.preload(queue.peek_advance());
+            }
+        }
+        *focused_pane = self.focused_pane;
+        *cursors = self.cursors;
+    }
+}
+
+fn session_file_path() -> PathBuf {
+    let mut session_path: PathBuf = dirs::config_dir().unwrap();
+    session_path.push("rsmus/session.bin");
+    return session_path;
+}
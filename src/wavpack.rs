@@ -0,0 +1,1106 @@
+// A WavPack decoder, as there is none in symphonia.
+//
+// A WavPack file is a run of blocks, each a header followed by metadata
+// sub-blocks. One block holds one or two channels of a stretch of the song
+// along with everything needed to decode it, so blocks can be decoded on
+// their own and seeking is done by skipping whole blocks. Songs with more
+// than two channels have several blocks for each stretch, the first marked
+// initial and the last final.
+//
+// The audio is coded as residuals, what is left after predicting each
+// sample from those before it (the decorrelation passes), written with an
+// adaptive Golomb-like code. Hybrid (lossy) files round the residuals,
+// which is why so much of the entropy decoder tracks an error limit.
+
+pub const HEADER_LEN: usize = 32;
+
+// Header flags.
+const BYTES_STORED: u32 = 0x3;
+const MONO_FLAG: u32 = 0x4;
+const HYBRID_FLAG: u32 = 0x8;
+const JOINT_STEREO: u32 = 0x10;
+const FLOAT_DATA: u32 = 0x80;
+const INT32_DATA: u32 = 0x100;
+const HYBRID_BITRATE: u32 = 0x200;
+const HYBRID_BALANCE: u32 = 0x400;
+const INITIAL_BLOCK: u32 = 0x800;
+const FINAL_BLOCK: u32 = 0x1000;
+const SHIFT_LSB: u32 = 13;
+const SHIFT_MASK: u32 = 0x1f << SHIFT_LSB;
+const SRATE_LSB: u32 = 23;
+const SRATE_MASK: u32 = 0xf << SRATE_LSB;
+// A mono block that is played as stereo.
+const FALSE_STEREO: u32 = 0x4000_0000;
+const DSD_FLAG: u32 = 0x8000_0000;
+const MONO_DATA: u32 = MONO_FLAG | FALSE_STEREO;
+
+// Sample rates the header can give, or the rate is in a sub-block.
+const SAMPLE_RATES: [u32; 15] = [
+    6000, 8000, 9600, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000,
+    64000, 88200, 96000, 192000,
+];
+
+// Sub-block ids.
+const ID_UNIQUE: u8 = 0x3f;
+const ID_ODD_SIZE: u8 = 0x40;
+const ID_LARGE: u8 = 0x80;
+const ID_DECORR_TERMS: u8 = 0x2;
+const ID_DECORR_WEIGHTS: u8 = 0x3;
+const ID_DECORR_SAMPLES: u8 = 0x4;
+const ID_ENTROPY_VARS: u8 = 0x5;
+const ID_HYBRID_PROFILE: u8 = 0x6;
+const ID_FLOAT_INFO: u8 = 0x8;
+const ID_INT32_INFO: u8 = 0x9;
+const ID_WV_BITSTREAM: u8 = 0xa;
+const ID_SAMPLE_RATE: u8 = 0x27;
+
+const MAX_TERMS: usize = 16;
+// Blocks are at most a second or two of audio, anything claiming to be much
+// more is damaged.
+const MAX_BLOCK_SAMPLES: u32 = 1 << 20;
+
+// Entropy coder constants: the longest run of ones before an escape and the
+// rate the slow level follows at.
+const LIMIT_ONES: u32 = 16;
+const SLS: u32 = 8;
+const SLO: u32 = 1 << (SLS - 1);
+
+// 2^(i/256) and log2(1 + i/256), scaled to a byte.
+const EXP2_TABLE: [u8; 256] = [
+    0, 1, 1, 2, 3, 3, 4, 5, 6, 6, 7, 8, 8, 9, 10, 11, 11, 12, 13, 14, 14, 15,
+    16, 16, 17, 18, 19, 19, 20, 21, 22, 22, 23, 24, 25, 25, 26, 27, 28, 29, 29,
+    30, 31, 32, 32, 33, 34, 35, 36, 36, 37, 38, 39, 40, 40, 41, 42, 43, 44, 44,
+    45, 46, 47, 48, 48, 49, 50, 51, 52, 53, 53, 54, 55, 56, 57, 58, 58, 59, 60,
+    61, 62, 63, 64, 65, 65, 66, 67, 68, 69, 70, 71, 72, 72, 73, 74, 75, 76, 77,
+    78, 79, 80, 81, 81, 82, 83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 94,
+    95, 96, 97, 98, 99, 100, 101, 102, 103, 104, 105, 106, 107, 108, 109, 110,
+    111, 112, 113, 114, 115, 116, 117, 118, 119, 120, 121, 122, 123, 124, 125,
+    126, 127, 128, 129, 130, 131, 132, 133, 135, 136, 137, 138, 139, 140, 141,
+    142, 143, 144, 145, 146, 147, 149, 150, 151, 152, 153, 154, 155, 156, 157,
+    159, 160, 161, 162, 163, 164, 165, 166, 168, 169, 170, 171, 172, 173, 175,
+    176, 177, 178, 179, 180, 182, 183, 184, 185, 186, 188, 189, 190, 191, 192,
+    194, 195, 196, 197, 198, 200, 201, 202, 203, 205, 206, 207, 208, 210, 211,
+    212, 214, 215, 216, 217, 219, 220, 221, 222, 224, 225, 226, 228, 229, 230,
+    232, 233, 234, 236, 237, 238, 240, 241, 242, 244, 245, 246, 248, 249, 250,
+    252, 253, 255,
+];
+const LOG2_TABLE: [u8; 256] = [
+    0, 1, 3, 4, 6, 7, 9, 10, 11, 13, 14, 16, 17, 18, 20, 21, 22, 24, 25, 26,
+    28, 29, 30, 32, 33, 34, 36, 37, 38, 40, 41, 42, 44, 45, 46, 47, 49, 50, 51,
+    52, 54, 55, 56, 57, 59, 60, 61, 62, 63, 65, 66, 67, 68, 69, 71, 72, 73, 74,
+    75, 77, 78, 79, 80, 81, 82, 84, 85, 86, 87, 88, 89, 90, 92, 93, 94, 95, 96,
+    97, 98, 99, 100, 102, 103, 104, 105, 106, 107, 108, 109, 110, 111, 112,
+    113, 114, 116, 117, 118, 119, 120, 121, 122, 123, 124, 125, 126, 127, 128,
+    129, 130, 131, 132, 133, 134, 135, 136, 137, 138, 139, 140, 141, 142, 143,
+    144, 145, 146, 147, 148, 149, 150, 151, 152, 153, 154, 155, 155, 156, 157,
+    158, 159, 160, 161, 162, 163, 164, 165, 166, 167, 168, 169, 169, 170, 171,
+    172, 173, 174, 175, 176, 177, 178, 178, 179, 180, 181, 182, 183, 184, 185,
+    185, 186, 187, 188, 189, 190, 191, 192, 192, 193, 194, 195, 196, 197, 198,
+    198, 199, 200, 201, 202, 203, 203, 204, 205, 206, 207, 208, 208, 209, 210,
+    211, 212, 212, 213, 214, 215, 216, 216, 217, 218, 219, 220, 220, 221, 222,
+    223, 224, 224, 225, 226, 227, 228, 228, 229, 230, 231, 231, 232, 233, 234,
+    234, 235, 236, 237, 238, 238, 239, 240, 241, 241, 242, 243, 244, 244, 245,
+    246, 247, 247, 248, 249, 249, 250, 251, 252, 252, 253, 254, 255, 255,
+];
+
+// The fixed part of a block.
+#[derive(Clone, Copy)]
+pub struct Header {
+    // Bytes in the block after the header.
+    pub size: u32,
+    pub version: u16,
+    // Where in the song, in samples per channel, the block starts.
+    pub block_index: u64,
+    pub total_samples: Option<u64>,
+    // Samples per channel in the block. Blocks without any only hold
+    // metadata.
+    pub block_samples: u32,
+    pub flags: u32,
+    pub crc: u32,
+}
+
+impl Header {
+    pub fn parse(bytes: &[u8; HEADER_LEN]) -> Option<Header> {
+        let word = |at: usize| {
+            return u32::from_le_bytes([
+                bytes[at],
+                bytes[at + 1],
+                bytes[at + 2],
+                bytes[at + 3],
+            ]);
+        };
+        if &bytes[0..4] != b"wvpk" {
+            return None;
+        }
+        let size = word(4);
+        let version = u16::from_le_bytes([bytes[8], bytes[9]]);
+        if !(0x402..=0x410).contains(&version)
+            || !(24..=1 << 24).contains(&size)
+        {
+            return None;
+        }
+        let block_samples = word(20);
+        if block_samples > MAX_BLOCK_SAMPLES {
+            return None;
+        }
+        // Both counts have a fifth byte. A total of all ones is unknown, and
+        // the encoder skips that value in each 2^32, so the high byte is
+        // taken off again.
+        let total = word(12);
+        let total_samples = if total == u32::MAX {
+            None
+        } else {
+            let high = bytes[11] as u64;
+            Some(total as u64 + (high << 32) - high)
+        };
+        return Some(Header {
+            size: size - 24,
+            version: version,
+            block_index: word(16) as u64 + ((bytes[10] as u64) << 32),
+            total_samples: total_samples,
+            block_samples: block_samples,
+            flags: word(24),
+            crc: word(28),
+        });
+    }
+
+    pub fn is_initial(&self) -> bool {
+        return self.flags & INITIAL_BLOCK != 0;
+    }
+
+    pub fn is_final(&self) -> bool {
+        return self.flags & FINAL_BLOCK != 0;
+    }
+
+    fn is_mono(&self) -> bool {
+        return self.flags & MONO_DATA != 0;
+    }
+}
+
+// Decoded audio of a block.
+pub struct Block {
+    pub channels: usize,
+    // None if neither the header nor the block says.
+    pub sample_rate: Option<u32>,
+    // Interleaved.
+    pub samples: Vec<i16>,
+}
+
+// One decorrelation pass. Positive terms predict from that many samples
+// back (17 and 18 extrapolate from the last two), negative ones from the
+// other channel.
+#[derive(Clone, Copy, Default)]
+struct Pass {
+    term: i32,
+    delta: i32,
+    weight_a: i32,
+    weight_b: i32,
+    samples_a: [i32; 8],
+    samples_b: [i32; 8],
+}
+
+// Entropy coder state kept for each channel.
+#[derive(Clone, Copy, Default)]
+struct Channel {
+    median: [u32; 3],
+    slow_level: u32,
+    error_limit: u32,
+}
+
+// Entropy coder state shared by the channels.
+#[derive(Default)]
+struct Words {
+    channels: [Channel; 2],
+    bitrate_delta: [u32; 2],
+    bitrate_acc: [u32; 2],
+    holding_one: u32,
+    holding_zero: bool,
+    zeros_acc: u32,
+}
+
+// Bits are read from the low end of each byte. Past the end they are all
+// ones, which runs the unary counts into their limits and so ends decoding.
+struct Bits<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl<'a> Bits<'a> {
+    fn new(data: &'a [u8]) -> Bits<'a> {
+        return Bits {
+            data: data,
+            position: 0,
+            buffer: 0,
+            count: 0,
+        };
+    }
+
+    fn bit(&mut self) -> u32 {
+        if self.count == 0 {
+            self.buffer = match self.data.get(self.position) {
+                Some(&byte) => byte as u32,
+                None => 0xff,
+            };
+            self.position += 1;
+            self.count = 8;
+        }
+        let bit = self.buffer & 1;
+        self.buffer >>= 1;
+        self.count -= 1;
+        return bit;
+    }
+
+    fn bits(&mut self, count: u32) -> u32 {
+        let mut value = 0;
+        for shift in 0..count {
+            value |= self.bit() << shift;
+        }
+        return value;
+    }
+
+    // Count ones up to the first zero, giving up after `limit`.
+    fn unary(&mut self, limit: u32) -> u32 {
+        let mut count = 0;
+        while count < limit && self.bit() == 1 {
+            count += 1;
+        }
+        return count;
+    }
+
+    // A count given as its length in unary then the bits below its top one.
+    // None if the length runs on past what fits.
+    fn escape(&mut self) -> Option<u32> {
+        let length = self.unary(33);
+        if length == 33 {
+            return None;
+        }
+        if length < 2 {
+            return Some(length);
+        }
+        return Some(self.bits(length - 1) | 1 << (length - 1));
+    }
+
+    // A value from 0 to `max`, in as few bits as that range needs.
+    fn code(&mut self, max: u32) -> u32 {
+        if max < 2 {
+            return if max == 1 { self.bit() } else { 0 };
+        }
+        let length = 32 - max.leading_zeros();
+        let extras = (1 << length) - max - 1;
+        let code = self.bits(length - 1);
+        if code < extras {
+            return code;
+        }
+        return (code << 1) - extras + self.bit();
+    }
+}
+
+// Decode the block with `header` from the bytes after the header.
+// Damaged audio comes out as silence, only blocks that can't be understood
+// at all are errors.
+pub fn decode_block(header: &Header, body: &[u8]) -> Result<Block, String> {
+    if header.flags & DSD_FLAG != 0 {
+        return Err("DSD WavPack isn't supported".to_string());
+    }
+    let mono = header.is_mono();
+    let mut passes: Vec<Pass> = Vec::new();
+    let mut words = Words::default();
+    let mut bitstream = None;
+    let mut float_shift = 0;
+    let mut float_exp = 0;
+    let mut int32 = [0; 4];
+    let mut sample_rate = match (header.flags & SRATE_MASK) >> SRATE_LSB {
+        15 => None,
+        index => Some(SAMPLE_RATES[index as usize]),
+    };
+    let mut rest = body;
+    while rest.len() >= 2 {
+        let id = rest[0];
+        let (words_len, start) = if id & ID_LARGE != 0 {
+            if rest.len() < 4 {
+                return Err("damaged WavPack block".to_string());
+            }
+            let len = rest[1] as usize
+                | (rest[2] as usize) << 8
+                | (rest[3] as usize) << 16;
+            (len, 4)
+        } else {
+            (rest[1] as usize, 2)
+        };
+        let end = start + words_len * 2;
+        if rest.len() < end {
+            return Err("damaged WavPack block".to_string());
+        }
+        let mut data = &rest[start..end];
+        if id & ID_ODD_SIZE != 0 && !data.is_empty() {
+            data = &data[..data.len() - 1];
+        }
+        rest = &rest[end..];
+        match id & ID_UNIQUE {
+            ID_DECORR_TERMS => passes = read_terms(data, mono)?,
+            ID_DECORR_WEIGHTS => read_weights(data, mono, &mut passes)?,
+            ID_DECORR_SAMPLES => read_samples(data, header, &mut passes),
+            ID_ENTROPY_VARS => read_entropy(data, mono, &mut words)?,
+            ID_HYBRID_PROFILE => read_hybrid(data, header, &mut words),
+            ID_FLOAT_INFO if data.len() >= 4 => {
+                float_shift = data[1] as u32;
+                float_exp = data[2] as i32;
+            }
+            ID_INT32_INFO if data.len() >= 4 => {
+                int32.copy_from_slice(&data[..4]);
+            }
+            ID_WV_BITSTREAM => bitstream = Some(data),
+            ID_SAMPLE_RATE if data.len() >= 3 => {
+                let mut bytes = [0; 4];
+                let len = data.len().min(4);
+                bytes[..len].copy_from_slice(&data[..len]);
+                sample_rate = Some(u32::from_le_bytes(bytes));
+            }
+            // Correction and extra float bits aren't needed for 16 bit
+            // output, the rest is for other programs.
+            _ => {}
+        }
+    }
+    let channels = if mono { 1 } else { 2 };
+    let frames = header.block_samples as usize;
+    let mut samples = vec![0; frames * channels];
+    let bitstream = match bitstream {
+        Some(bitstream) => bitstream,
+        None if frames == 0 => &[][..],
+        None => return Err("WavPack block without audio".to_string()),
+    };
+    let mut bits = Bits::new(bitstream);
+    let mut damaged = false;
+    for (index, sample) in samples.iter_mut().enumerate() {
+        let channel = if mono { 0 } else { index & 1 };
+        match get_word(&mut words, &mut bits, header.flags, channel) {
+            Some(word) => *sample = word,
+            None => {
+                damaged = true;
+                break;
+            }
+        }
+    }
+    if !damaged {
+        for pass in passes.iter_mut() {
+            if channels == 1 {
+                decorrelate_mono(pass, &mut samples);
+            } else {
+                decorrelate_stereo(pass, &mut samples);
+            }
+        }
+        if channels == 2 && header.flags & JOINT_STEREO != 0 {
+            for pair in samples.chunks_mut(2) {
+                pair[1] = pair[1].wrapping_sub(pair[0] >> 1);
+                pair[0] = pair[0].wrapping_add(pair[1]);
+            }
+        }
+        damaged = checksum(&samples, channels) != header.crc;
+    }
+    let mut output: Vec<i16> = if damaged {
+        vec![0; samples.len()]
+    } else if header.flags & FLOAT_DATA != 0 {
+        samples
+            .iter()
+            .map(|&sample| float_to_i16(sample, float_shift, float_exp))
+            .collect()
+    } else {
+        let mut shift = (header.flags & SHIFT_MASK) >> SHIFT_LSB;
+        if header.flags & INT32_DATA != 0 {
+            shift += int32.iter().map(|&bits| bits as u32).sum::<u32>();
+        }
+        samples
+            .iter()
+            .map(|&sample| int_to_i16(sample, shift, header.flags))
+            .collect()
+    };
+    // False stereo blocks hold one channel that is played on both.
+    let output_channels = if header.flags & MONO_FLAG == 0 { 2 } else { 1 };
+    if output_channels > channels {
+        let mut doubled = Vec::with_capacity(output.len() * 2);
+        for sample in output {
+            doubled.push(sample);
+            doubled.push(sample);
+        }
+        output = doubled;
+    }
+    return Ok(Block {
+        channels: output_channels,
+        sample_rate: sample_rate,
+        samples: output,
+    });
+}
+
+// Terms and deltas of the passes, a byte each, written last pass first.
+fn read_terms(data: &[u8], mono: bool) -> Result<Vec<Pass>, String> {
+    if data.len() > MAX_TERMS {
+        return Err("too many WavPack decorrelation passes".to_string());
+    }
+    let mut passes = Vec::new();
+    for &byte in data.iter().rev() {
+        let term = (byte & 0x1f) as i32 - 5;
+        let valid = match term {
+            1..=8 | 17 | 18 => true,
+            -3..=-1 => !mono,
+            _ => false,
+        };
+        if !valid {
+            return Err("bad WavPack decorrelation term".to_string());
+        }
+        passes.push(Pass {
+            term: term,
+            delta: ((byte >> 5) & 0x7) as i32,
+            ..Default::default()
+        });
+    }
+    return Ok(passes);
+}
+
+// Starting weights, a byte per channel, from the last pass back.
+fn read_weights(
+    data: &[u8],
+    mono: bool,
+    passes: &mut [Pass],
+) -> Result<(), String> {
+    let per_pass = if mono { 1 } else { 2 };
+    if data.len() / per_pass > passes.len() {
+        return Err("too many WavPack decorrelation weights".to_string());
+    }
+    for (pass, bytes) in passes.iter_mut().rev().zip(data.chunks(per_pass)) {
+        pass.weight_a = restore_weight(bytes[0] as i8);
+        if !mono && bytes.len() == 2 {
+            pass.weight_b = restore_weight(bytes[1] as i8);
+        }
+    }
+    return Ok(());
+}
+
+fn restore_weight(weight: i8) -> i32 {
+    let weight = (weight as i32) << 3;
+    if weight > 0 {
+        return weight + ((weight + 64) >> 7);
+    }
+    return weight;
+}
+
+// Sample history the passes start with, logs in 16 bits each, from the
+// last pass back for as long as there is data.
+fn read_samples(data: &[u8], header: &Header, passes: &mut [Pass]) {
+    let mono = header.is_mono();
+    let mut values = data
+        .chunks_exact(2)
+        .map(|bytes| exp2s(i16::from_le_bytes([bytes[0], bytes[1]]) as i32));
+    // The first release of the format put something else first in hybrid
+    // files.
+    if header.version == 0x402 && header.flags & HYBRID_FLAG != 0 {
+        values.next();
+        if !mono {
+            values.next();
+        }
+    }
+    for pass in passes.iter_mut().rev() {
+        let count = if pass.term > 8 {
+            2
+        } else if pass.term < 0 {
+            1
+        } else {
+            pass.term as usize
+        };
+        let needed = if mono { count } else { count * 2 };
+        let read: Vec<i32> = values.by_ref().take(needed).collect();
+        if read.len() < needed {
+            return;
+        }
+        if pass.term < 0 {
+            pass.samples_a[0] = read[0];
+            pass.samples_b[0] = read[1];
+        } else if mono {
+            pass.samples_a[..count].copy_from_slice(&read);
+        } else {
+            for index in 0..count {
+                pass.samples_a[index] = read[index * 2];
+                pass.samples_b[index] = read[index * 2 + 1];
+            }
+        }
+    }
+}
+
+// The entropy coder's starting medians, three for each channel.
+fn read_entropy(
+    data: &[u8],
+    mono: bool,
+    words: &mut Words,
+) -> Result<(), String> {
+    if data.len() != if mono { 6 } else { 12 } {
+        return Err("bad WavPack entropy variables".to_string());
+    }
+    for (index, bytes) in data.chunks(2).enumerate() {
+        let value = exp2s(u16::from_le_bytes([bytes[0], bytes[1]]) as i32);
+        words.channels[index / 3].median[index % 3] = value as u32;
+    }
+    return Ok(());
+}
+
+// How a hybrid file's bitrate starts out and changes over the block.
+fn read_hybrid(data: &[u8], header: &Header, words: &mut Words) {
+    let channels = if header.is_mono() { 1 } else { 2 };
+    let mut values = data
+        .chunks_exact(2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+    if header.flags & HYBRID_BITRATE != 0 {
+        for channel in 0..channels {
+            let value = values.next().unwrap_or(0) as i32;
+            words.channels[channel].slow_level = exp2s(value) as u32;
+        }
+    }
+    for channel in 0..channels {
+        words.bitrate_acc[channel] = (values.next().unwrap_or(0) as u32) << 16;
+    }
+    for channel in 0..channels {
+        words.bitrate_delta[channel] = match values.next() {
+            Some(value) => exp2s(value as i16 as i32) as u32,
+            None => 0,
+        };
+    }
+}
+
+// 2 to the power of `log`, which has 8 fractional bits.
+fn exp2s(log: i32) -> i32 {
+    if log < 0 {
+        return -exp2s(-log);
+    }
+    let value = EXP2_TABLE[(log & 0xff) as usize] as u32 | 0x100;
+    let shift = log >> 8;
+    if shift <= 9 {
+        return (value >> (9 - shift)) as i32;
+    }
+    return value.wrapping_shl((shift - 9) as u32) as i32;
+}
+
+// The base 2 log of `value` with 8 fractional bits, rounded a little up.
+fn log2(value: u32) -> u32 {
+    let value = value.wrapping_add(value >> 9);
+    let bits = 32 - value.leading_zeros();
+    let fraction = if bits < 9 {
+        value << (9 - bits)
+    } else {
+        value >> (bits - 9)
+    };
+    return (bits << 8) + LOG2_TABLE[(fraction & 0xff) as usize] as u32;
+}
+
+fn update_error_limit(words: &mut Words, flags: u32) {
+    let channels = if flags & MONO_DATA != 0 { 1 } else { 2 };
+    let mut bitrates = [0; 2];
+    let mut slow_logs = [0; 2];
+    for (channel, entropy) in words.channels.iter().enumerate().take(channels) {
+        let acc = &mut words.bitrate_acc[channel];
+        *acc = acc.wrapping_add(words.bitrate_delta[channel]);
+        bitrates[channel] = (*acc >> 16) as i32;
+        slow_logs[channel] =
+            (entropy.slow_level.wrapping_add(SLO) >> SLS) as i32;
+    }
+    let by_level = flags & HYBRID_BITRATE != 0;
+    if by_level && channels == 2 && flags & HYBRID_BALANCE != 0 {
+        let balance = (slow_logs[1] - slow_logs[0] + bitrates[1] + 1) >> 1;
+        if balance > bitrates[0] {
+            bitrates = [0, bitrates[0] * 2];
+        } else if -balance > bitrates[0] {
+            bitrates = [bitrates[0] * 2, 0];
+        } else {
+            bitrates = [bitrates[0] - balance, bitrates[0] + balance];
+        }
+    }
+    for (channel, entropy) in
+        words.channels.iter_mut().enumerate().take(channels)
+    {
+        let log = slow_logs[channel] - bitrates[channel];
+        entropy.error_limit = if !by_level {
+            exp2s(bitrates[channel]) as u32
+        } else if log > -0x100 {
+            exp2s(log + 0x100) as u32
+        } else {
+            0
+        };
+    }
+}
+
+// The next residual for `channel`. None when the bitstream runs out.
+fn get_word(
+    words: &mut Words,
+    bits: &mut Bits,
+    flags: u32,
+    channel: usize,
+) -> Option<i32> {
+    // With both channels quiet, runs of zeros are coded as their length.
+    if words.channels[0].median[0] & !1 == 0
+        && words.channels[1].median[0] & !1 == 0
+        && !words.holding_zero
+        && words.holding_one == 0
+    {
+        if words.zeros_acc > 0 {
+            words.zeros_acc -= 1;
+        } else {
+            words.zeros_acc = bits.escape()?;
+            if words.zeros_acc > 0 {
+                words.channels[0].median = [0; 3];
+                words.channels[1].median = [0; 3];
+            }
+        }
+        if words.zeros_acc > 0 {
+            let entropy = &mut words.channels[channel];
+            entropy.slow_level -= entropy.slow_level.wrapping_add(SLO) >> SLS;
+            return Some(0);
+        }
+    }
+    let mut ones = 0;
+    if words.holding_zero {
+        words.holding_zero = false;
+    } else {
+        ones = bits.unary(LIMIT_ONES + 1);
+        if ones == LIMIT_ONES + 1 {
+            return None;
+        }
+        if ones == LIMIT_ONES {
+            ones += bits.escape()?;
+        }
+        if words.holding_one != 0 {
+            words.holding_one = ones & 1;
+            ones = (ones >> 1) + 1;
+        } else {
+            words.holding_one = ones & 1;
+            ones >>= 1;
+        }
+        words.holding_zero = words.holding_one == 0;
+    }
+    if flags & HYBRID_FLAG != 0 && channel == 0 {
+        update_error_limit(words, flags);
+    }
+    let entropy = &mut words.channels[channel];
+    let median = &mut entropy.median;
+    let get = |median: &[u32; 3], index: usize| (median[index] >> 4) + 1;
+    let mut low;
+    let mut high;
+    if ones == 0 {
+        low = 0;
+        high = get(median, 0) - 1;
+        decrease(median, 0, 128);
+    } else {
+        low = get(median, 0);
+        increase(median, 0, 128);
+        if ones == 1 {
+            high = low.wrapping_add(get(median, 1) - 1);
+            decrease(median, 1, 64);
+        } else {
+            low = low.wrapping_add(get(median, 1));
+            increase(median, 1, 64);
+            if ones == 2 {
+                high = low.wrapping_add(get(median, 2) - 1);
+                decrease(median, 2, 32);
+            } else {
+                low = low.wrapping_add((ones - 2).wrapping_mul(get(median, 2)));
+                high = low.wrapping_add(get(median, 2) - 1);
+                increase(median, 2, 32);
+            }
+        }
+    }
+    low &= 0x7fff_ffff;
+    high &= 0x7fff_ffff;
+    if low > high {
+        high = low;
+    }
+    let mut mid = (high + low + 1) >> 1;
+    if entropy.error_limit == 0 {
+        mid = bits.code(high - low) + low;
+    } else {
+        while high - low > entropy.error_limit {
+            if bits.bit() == 1 {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+            mid = (high + low + 1) >> 1;
+        }
+    }
+    let negative = bits.bit() == 1;
+    if flags & HYBRID_BITRATE != 0 {
+        entropy.slow_level -= entropy.slow_level.wrapping_add(SLO) >> SLS;
+        entropy.slow_level = entropy.slow_level.wrapping_add(log2(mid));
+    }
+    if negative {
+        return Some(!(mid as i32));
+    }
+    return Some(mid as i32);
+}
+
+// The medians step up faster than down, by less the smaller they are.
+fn increase(median: &mut [u32; 3], index: usize, divisor: u32) {
+    let step = (median[index].wrapping_add(divisor) / divisor) * 5;
+    median[index] = median[index].wrapping_add(step);
+}
+
+fn decrease(median: &mut [u32; 3], index: usize, divisor: u32) {
+    let step = (median[index].wrapping_add(divisor - 2) / divisor) * 2;
+    median[index] = median[index].wrapping_sub(step);
+}
+
+fn apply_weight(weight: i32, sample: i32) -> i32 {
+    if sample as i16 as i32 == sample {
+        return (weight.wrapping_mul(sample) + 512) >> 10;
+    }
+    // Too big to multiply in 32 bits, so done in two halves.
+    let low = ((sample & 0xffff).wrapping_mul(weight)) >> 9;
+    let high = ((sample & !0xffff) >> 9).wrapping_mul(weight);
+    return low.wrapping_add(high).wrapping_add(1) >> 1;
+}
+
+// Weights move towards whatever predicts better.
+fn update_weight(weight: &mut i32, delta: i32, source: i32, result: i32) {
+    if source != 0 && result != 0 {
+        if (source ^ result) < 0 {
+            *weight -= delta;
+        } else {
+            *weight += delta;
+        }
+    }
+}
+
+fn update_weight_clip(weight: &mut i32, delta: i32, source: i32, result: i32) {
+    update_weight(weight, delta, source, result);
+    *weight = (*weight).clamp(-1024, 1024);
+}
+
+// Undo one pass on a single channel, running through the whole block.
+fn decorrelate_mono(pass: &mut Pass, samples: &mut [i32]) {
+    let delta = pass.delta;
+    if pass.term > 8 {
+        for sample in samples.iter_mut() {
+            let history = &mut pass.samples_a;
+            let predicted = extrapolate(pass.term, history);
+            history[1] = history[0];
+            history[0] =
+                apply_weight(pass.weight_a, predicted).wrapping_add(*sample);
+            update_weight(&mut pass.weight_a, delta, predicted, *sample);
+            *sample = history[0];
+        }
+        return;
+    }
+    let term = pass.term as usize;
+    let mut m = 0;
+    for sample in samples.iter_mut() {
+        let k = (m + term) & 7;
+        let source = pass.samples_a[m];
+        pass.samples_a[k] =
+            apply_weight(pass.weight_a, source).wrapping_add(*sample);
+        update_weight(&mut pass.weight_a, delta, source, *sample);
+        *sample = pass.samples_a[k];
+        m = (m + 1) & 7;
+    }
+    pass.samples_a.rotate_left(m);
+}
+
+// The same for a pair of channels, which negative terms mix.
+fn decorrelate_stereo(pass: &mut Pass, samples: &mut [i32]) {
+    let delta = pass.delta;
+    match pass.term {
+        -1 => {
+            for pair in samples.chunks_mut(2) {
+                let a = apply_weight(pass.weight_a, pass.samples_a[0])
+                    .wrapping_add(pair[0]);
+                update_weight_clip(
+                    &mut pass.weight_a,
+                    delta,
+                    pass.samples_a[0],
+                    pair[0],
+                );
+                pair[0] = a;
+                pass.samples_a[0] =
+                    apply_weight(pass.weight_b, a).wrapping_add(pair[1]);
+                update_weight_clip(&mut pass.weight_b, delta, a, pair[1]);
+                pair[1] = pass.samples_a[0];
+            }
+        }
+        -2 => {
+            for pair in samples.chunks_mut(2) {
+                let b = apply_weight(pass.weight_b, pass.samples_b[0])
+                    .wrapping_add(pair[1]);
+                update_weight_clip(
+                    &mut pass.weight_b,
+                    delta,
+                    pass.samples_b[0],
+                    pair[1],
+                );
+                pair[1] = b;
+                pass.samples_b[0] =
+                    apply_weight(pass.weight_a, b).wrapping_add(pair[0]);
+                update_weight_clip(&mut pass.weight_a, delta, b, pair[0]);
+                pair[0] = pass.samples_b[0];
+            }
+        }
+        -3 => {
+            for pair in samples.chunks_mut(2) {
+                let a = apply_weight(pass.weight_a, pass.samples_a[0])
+                    .wrapping_add(pair[0]);
+                update_weight_clip(
+                    &mut pass.weight_a,
+                    delta,
+                    pass.samples_a[0],
+                    pair[0],
+                );
+                let b = apply_weight(pass.weight_b, pass.samples_b[0])
+                    .wrapping_add(pair[1]);
+                update_weight_clip(
+                    &mut pass.weight_b,
+                    delta,
+                    pass.samples_b[0],
+                    pair[1],
+                );
+                pass.samples_b[0] = a;
+                pass.samples_a[0] = b;
+                pair[0] = a;
+                pair[1] = b;
+            }
+        }
+        17 | 18 => {
+            for pair in samples.chunks_mut(2) {
+                let predicted = extrapolate(pass.term, &pass.samples_a);
+                pass.samples_a[1] = pass.samples_a[0];
+                pass.samples_a[0] = apply_weight(pass.weight_a, predicted)
+                    .wrapping_add(pair[0]);
+                update_weight(&mut pass.weight_a, delta, predicted, pair[0]);
+                pair[0] = pass.samples_a[0];
+                let predicted = extrapolate(pass.term, &pass.samples_b);
+                pass.samples_b[1] = pass.samples_b[0];
+                pass.samples_b[0] = apply_weight(pass.weight_b, predicted)
+                    .wrapping_add(pair[1]);
+                update_weight(&mut pass.weight_b, delta, predicted, pair[1]);
+                pair[1] = pass.samples_b[0];
+            }
+        }
+        _ => {
+            let term = pass.term as usize;
+            let mut m = 0;
+            for pair in samples.chunks_mut(2) {
+                let k = (m + term) & 7;
+                let source = pass.samples_a[m];
+                pass.samples_a[k] =
+                    apply_weight(pass.weight_a, source).wrapping_add(pair[0]);
+                update_weight(&mut pass.weight_a, delta, source, pair[0]);
+                pair[0] = pass.samples_a[k];
+                let source = pass.samples_b[m];
+                pass.samples_b[k] =
+                    apply_weight(pass.weight_b, source).wrapping_add(pair[1]);
+                update_weight(&mut pass.weight_b, delta, source, pair[1]);
+                pair[1] = pass.samples_b[k];
+                m = (m + 1) & 7;
+            }
+            pass.samples_a.rotate_left(m);
+            pass.samples_b.rotate_left(m);
+        }
+    }
+}
+
+// Terms 17 and 18 continue the line through the last two samples, 18 only
+// half as steeply.
+fn extrapolate(term: i32, history: &[i32; 8]) -> i32 {
+    if term == 17 {
+        return history[0].wrapping_mul(2).wrapping_sub(history[1]);
+    }
+    return history[0].wrapping_mul(3).wrapping_sub(history[1]) >> 1;
+}
+
+// The checksum the header holds, over the decoded samples.
+fn checksum(samples: &[i32], channels: usize) -> u32 {
+    let mut crc: u32 = 0xffff_ffff;
+    if channels == 2 {
+        for pair in samples.chunks(2) {
+            crc = crc
+                .wrapping_mul(9)
+                .wrapping_add((pair[0] as u32).wrapping_mul(3))
+                .wrapping_add(pair[1] as u32);
+        }
+    } else {
+        for &sample in samples {
+            crc = crc.wrapping_mul(3).wrapping_add(sample as u32);
+        }
+    }
+    return crc;
+}
+
+// Samples are stored in 1 to 4 bytes, less `shift` low bits that are all
+// zero. Hybrid files can overshoot slightly, so are clipped.
+fn int_to_i16(sample: i32, shift: u32, flags: u32) -> i16 {
+    let bits = ((flags & BYTES_STORED) + 1) * 8;
+    let limit = 1i64 << (bits - 1);
+    let value = ((sample as i64) << shift.min(32))
+        .max(-limit)
+        .min(limit - 1);
+    if bits < 16 {
+        return (value << (16 - bits)) as i16;
+    }
+    return (value >> (bits - 16)) as i16;
+}
+
+// Floats are stored as integers that all share the largest exponent in the
+// block, less `shift` low bits that are all zero.
+fn float_to_i16(sample: i32, shift: u32, exponent: i32) -> i16 {
+    let value = (sample as i64) << shift.min(32);
+    let float = value as f64 * 2f64.powi(exponent - 150);
+    return (float.clamp(-1.0, 1.0) * 32767.0) as i16;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A block header for `frames` samples per channel of 16 bit audio.
+    fn header(frames: u32, flags: u32, crc: u32) -> Header {
+        return Header {
+            size: 0,
+            version: 0x407,
+            block_index: 0,
+            total_samples: Some(frames as u64),
+            block_samples: frames,
+            flags: 1 | 9 << SRATE_LSB | INITIAL_BLOCK | FINAL_BLOCK | flags,
+            crc: crc,
+        };
+    }
+
+    // A sub-block holding `data`.
+    fn sub_block(id: u8, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![id, ((data.len() + 1) / 2) as u8];
+        if data.len() % 2 == 1 {
+            bytes[0] |= ID_ODD_SIZE;
+        }
+        bytes.extend_from_slice(data);
+        bytes.resize(bytes.len() + data.len() % 2, 0);
+        return bytes;
+    }
+
+    #[test]
+    fn reads_block_headers() {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..4].copy_from_slice(b"wvpk");
+        bytes[4..8].copy_from_slice(&1000u32.to_le_bytes());
+        bytes[8..10].copy_from_slice(&0x410u16.to_le_bytes());
+        bytes[12..16].copy_from_slice(&882000u32.to_le_bytes());
+        bytes[16..20].copy_from_slice(&44100u32.to_le_bytes());
+        bytes[20..24].copy_from_slice(&22050u32.to_le_bytes());
+        bytes[24..28]
+            .copy_from_slice(&(INITIAL_BLOCK | MONO_FLAG).to_le_bytes());
+        let header = Header::parse(&bytes).unwrap();
+        assert_eq!(header.size, 976);
+        assert_eq!(header.block_index, 44100);
+        assert_eq!(header.total_samples, Some(882000));
+        assert_eq!(header.block_samples, 22050);
+        assert!(header.is_initial() && !header.is_final() && header.is_mono());
+        bytes[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Header::parse(&bytes).unwrap().total_samples, None);
+        bytes[8..10].copy_from_slice(&0x300u16.to_le_bytes());
+        assert!(Header::parse(&bytes).is_none());
+    }
+
+    #[test]
+    fn converts_logs() {
+        assert_eq!(exp2s(0), 0);
+        assert_eq!(exp2s(9 << 8), 256);
+        assert_eq!(exp2s(16 << 8), 32768);
+        assert_eq!(exp2s(-(16 << 8)), -32768);
+        assert_eq!(log2(0), 0);
+        assert_eq!(log2(1), 256);
+        assert_eq!(log2(256), 9 << 8);
+        // The two are near enough inverses.
+        for value in [3u32, 100, 1000, 44100, 1 << 20].iter() {
+            let back = exp2s(log2(*value) as i32) as i64;
+            assert!((back - *value as i64).abs() <= (*value as i64 >> 6) + 1);
+        }
+    }
+
+    #[test]
+    fn reads_codes() {
+        // Values up to 5 take two bits below 2 and three from there,
+        // 1, 4 and 5 here.
+        let mut bits = Bits::new(&[0b1110_1101]);
+        assert_eq!(bits.code(5), 1);
+        assert_eq!(bits.code(5), 4);
+        assert_eq!(bits.code(5), 5);
+        // A run length of 100: seven in unary, then its six low bits.
+        let mut bits = Bits::new(&[0b0111_1111, 0b0010_0100]);
+        assert_eq!(bits.escape(), Some(100));
+        // Past the end it is all ones.
+        let mut bits = Bits::new(&[]);
+        assert_eq!(bits.escape(), None);
+    }
+
+    #[test]
+    fn undoes_decorrelation() {
+        // A weight of 1024 predicts each sample is the one before, so the
+        // residuals add up.
+        let mut pass = Pass {
+            term: 1,
+            weight_a: 1024,
+            ..Default::default()
+        };
+        let mut samples = vec![5, 3, -2, 0, 1];
+        decorrelate_mono(&mut pass, &mut samples);
+        assert_eq!(samples, vec![5, 8, 6, 6, 7]);
+        assert_eq!(pass.samples_a[0], 7);
+        // Term 17 continues the slope.
+        let mut pass = Pass {
+            term: 17,
+            weight_a: 1024,
+            ..Default::default()
+        };
+        let mut samples = vec![2, 0, 0, 0];
+        decorrelate_mono(&mut pass, &mut samples);
+        assert_eq!(samples, vec![2, 4, 6, 8]);
+    }
+
+    #[test]
+    fn decodes_silence() {
+        // Zero medians and a run of 100 zeros.
+        let mut body = sub_block(ID_ENTROPY_VARS, &[0; 12]);
+        body.extend(sub_block(ID_WV_BITSTREAM, &[0b0111_1111, 0b0010_0100]));
+        let crc = checksum(&[0; 100], 2);
+        let block = decode_block(&header(50, JOINT_STEREO, crc), &body);
+        let block = block.unwrap();
+        assert_eq!(block.channels, 2);
+        assert_eq!(block.sample_rate, Some(44100));
+        assert_eq!(block.samples, vec![0; 100]);
+        // Mono played as stereo.
+        let mut body = sub_block(ID_ENTROPY_VARS, &[0; 6]);
+        body.extend(sub_block(ID_WV_BITSTREAM, &[0b0111_1111, 0b0010_0100]));
+        let crc = checksum(&[0; 100], 1);
+        let flags = FALSE_STEREO;
+        let block = decode_block(&header(100, flags, crc), &body).unwrap();
+        assert_eq!(block.channels, 2);
+        assert_eq!(block.samples.len(), 200);
+    }
+
+    #[test]
+    fn decodes_residuals() {
+        // Medians of 16 give codes of 0 to 1 for no ones, then ranges of two
+        // above that for each one. Bits are the ones (halved, the odd one
+        // held over to the next sample), the code and the sign:
+        // 0 1 0 is 1, a held zero then 1 is -1 (the range having shrunk to
+        // just 0), 11111 0 1 0 is 4 and 0 0 1 (with the held one) is -3.
+        // Stored as logs.
+        let median: u16 = 5 << 8;
+        let mut vars = Vec::new();
+        for _ in 0..3 {
+            vars.extend_from_slice(&median.to_le_bytes());
+        }
+        let stream = [0b1111_1010, 0b0100_0101];
+        let mut body = sub_block(ID_ENTROPY_VARS, &vars);
+        body.extend(sub_block(ID_WV_BITSTREAM, &stream));
+        let samples = [1, -1, 4, -3];
+        let crc = checksum(&samples, 1);
+        let block = decode_block(&header(4, MONO_FLAG, crc), &body).unwrap();
+        assert_eq!(block.samples, vec![1, -1, 4, -3]);
+        // A wrong checksum is silence.
+        let block = decode_block(&header(4, MONO_FLAG, 0), &body).unwrap();
+        assert_eq!(block.samples, vec![0; 4]);
+    }
+}